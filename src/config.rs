@@ -1,61 +1,177 @@
+use super::devices;
+use super::document::{self, ConfigDocument, Segment};
+use super::learn;
+use super::preprocess;
+use super::prompts;
 use super::types;
-use super::util;
+use super::util::{self, Logger};
+use super::validate;
 use anyhow::Error;
-use config::ConfigError;
-use home::home_dir;
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
-use std::path::PathBuf;
-// TODO:Major Add interactive system and sub-subcommands to interactively generate and modify the config files
-//   Replace the `--generate` for a `new` subcommand. Make `new` generate a empty file unless it uses the flag `--skeleton`
-//   Add a `add` subcommand
-//   Add a `add device` subcommand to generate an entry on the `config` array
-//   Add a `add control` subcommand to insert a new control for the selected device
-//   Make the commands interactive via interaction with stdin
+use std::time::Duration;
+
+const EMPTY_CONFIG: &str = r#"{
+    "$schema": "https://raw.githubusercontent.com/aordano/control-board/master/schema/midiboard.schema.json",
+    "log_level": "Warn",
+    "config": []
+}
+"#;
+
+const SCHEMA_URL: &str =
+    "https://raw.githubusercontent.com/aordano/control-board/master/schema/midiboard.schema.json";
 
 pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
-    let generate = cli
-        .get_one::<bool>("generate")
-        .ok_or(Error::msg("Bad --generate command."))?;
+    // Clap can only make arguments conflict with every subcommand at once, and --path and
+    // --format go with all of them
+    if cli.get_one::<bool>("validate") == Some(&true) && cli.subcommand().is_some() {
+        return Err(Error::msg(
+            "--validate can't be used with a subcommand, use `config validate` instead.",
+        ));
+    }
+    match cli.subcommand() {
+        Some(("learn", sub_m)) => return learn::run(sub_m),
+        Some(("new", sub_m)) => {
+            let skeleton = sub_m
+                .get_one::<bool>("skeleton")
+                .ok_or(Error::msg("Bad --skeleton command."))?;
+            let (path, format) = util::config_location(sub_m)?;
+            return generate_config(&path, format, *skeleton);
+        }
+        Some(("add", sub_m)) => {
+            let (path, format) = util::config_location(sub_m)?;
+            return match sub_m.subcommand() {
+                Some(("device", _)) => add_device(&path, format),
+                Some(("control", add_m)) => {
+                    let device = add_m
+                        .get_one::<String>("device")
+                        .ok_or(Error::msg("No device name provided"))?;
+                    add_control(&path, format, device)
+                }
+                _ => Err(Error::msg("Nothing to add.")),
+            };
+        }
+        Some(("validate", sub_m)) => {
+            let probe = sub_m
+                .get_one::<bool>("probe")
+                .ok_or(Error::msg("Bad --probe command."))?;
+            let listen = sub_m.get_one::<u64>("listen").copied();
+            let (path, format) = util::config_location(sub_m)?;
+            return validate_config(&path, format, probe.then_some(Probe { listen }));
+        }
+        Some(("convert", sub_m)) => {
+            let (path, format) = util::config_location(sub_m)?;
+            let target = sub_m
+                .get_one::<types::ConfigFormat>("to")
+                .ok_or(Error::msg("No format to convert to"))?;
+            let output = match sub_m.get_one::<String>("output") {
+                Some(output) => Path::new(output).to_path_buf(),
+                None => path.with_extension(target.extension()),
+            };
+            return convert_config(&path, format, &output, *target);
+        }
+        Some(("path", sub_m)) => {
+            let location = util::find_config(
+                sub_m.get_one::<String>("path"),
+                sub_m.get_one::<types::ConfigFormat>("format"),
+            )?;
+            show_location(&location);
+            return Ok(());
+        }
+        Some(("schema", _)) => {
+            println!("{}", serde_json::to_string_pretty(&schema())?);
+            return Ok(());
+        }
+        Some(("get", sub_m)) => {
+            let (path, format) = util::config_location(sub_m)?;
+            let key = sub_m
+                .get_one::<String>("key")
+                .ok_or(Error::msg("No key provided"))?;
+            return get_value(&path, format, key);
+        }
+        Some(("set", sub_m)) => {
+            let (path, format) = util::config_location(sub_m)?;
+            let key = sub_m
+                .get_one::<String>("key")
+                .ok_or(Error::msg("No key provided"))?;
+            let value = sub_m
+                .get_one::<String>("value")
+                .ok_or(Error::msg("No value provided"))?;
+            let as_string = sub_m
+                .get_one::<bool>("string")
+                .ok_or(Error::msg("Bad --string command."))?;
+            return set_value(&path, format, key, value, *as_string);
+        }
+        Some(("remove", sub_m)) => {
+            let (path, format) = util::config_location(sub_m)?;
+            let mut document = ConfigDocument::load(&path, format)?;
+            match sub_m.subcommand() {
+                Some(("control", remove_m)) => {
+                    let name = remove_m
+                        .get_one::<String>("name")
+                        .ok_or(Error::msg("No control name provided"))?;
+                    let device = remove_m
+                        .get_one::<String>("device")
+                        .ok_or(Error::msg("No device name provided"))?;
+                    document.remove_control(device, name)?
+                }
+                Some(("device", remove_m)) => {
+                    let name = remove_m
+                        .get_one::<String>("name")
+                        .ok_or(Error::msg("No device name provided"))?;
+                    document.remove_device(name)?
+                }
+                _ => {
+                    let key = sub_m
+                        .get_one::<String>("key")
+                        .ok_or(Error::msg("Nothing to remove"))?;
+                    document.remove(&document::parse_path(key)?)?
+                }
+            }
+            return document.save();
+        }
+        _ => {}
+    }
+
     let validate = cli
         .get_one::<bool>("validate")
         .ok_or(Error::msg("Bad --validate command."))?;
-    let path = cli.get_one::<String>("path");
-
-    if *generate {
-        return generate_config(path);
-    }
-
     if *validate {
-        return validate_config(path);
+        let (path, format) = util::config_location(cli)?;
+        return validate_config(&path, format, None);
     }
 
     panic!("No valid argument provided to the config subcommand.")
 }
 
-fn generate_config(path: Option<&String>) -> Result<(), Error> {
-    let skeleton = types::Asset::get("midiboard.json")
-        .ok_or(Error::msg("Could not load the skeleton file"))?;
-    let mut fullpath = PathBuf::new();
-
-    match path {
-        None => {
-            fullpath.push(
-                home_dir().ok_or(ConfigError::Message(String::from("Could not parse path")))?,
-            );
+pub fn generate_config(
+    fullpath: &Path,
+    format: types::ConfigFormat,
+    skeleton: bool,
+) -> Result<(), Error> {
+    let contents = if skeleton {
+        let asset = types::Asset::get("midiboard.json")
+            .ok_or(Error::msg("Could not load the skeleton file"))?;
+        String::from_utf8(asset.data.into_owned())?
+    } else {
+        EMPTY_CONFIG.to_string()
+    };
+    // The templates are written in JSON, other formats are translated from them
+    let contents = match format {
+        types::ConfigFormat::Json => contents,
+        format => util::render_value(&serde_json::from_str(&contents)?, format)?,
+    };
 
-            fullpath.push("midiboard");
-            fullpath.set_extension("json");
-        }
-        Some(path) => fullpath.push(path),
-    }
-    return match Path::try_exists(&fullpath) {
+    return match Path::try_exists(fullpath) {
         Ok(exists) => match exists {
             true => Err(Error::msg(util::string_to_sstr(format!(
                 "File already exists in path {:?}",
                 fullpath
             )))),
-            false => Ok(fs::write(fullpath, skeleton.data)?),
+            false => Ok(fs::write(fullpath, contents)?),
         },
         Err(_) => Err(Error::msg(util::string_to_sstr(format!(
             "Cannot access path {:?}",
@@ -64,25 +180,331 @@ fn generate_config(path: Option<&String>) -> Result<(), Error> {
     };
 }
 
-fn validate_config(path: Option<&String>) -> Result<(), Error> {
-    let config = util::read_user_config(path);
-
-    return match config {
-        Ok(data) => {
-            let log = util::Logger::new(data.log_level);
-            log.dynamic(
-                format!(
-                    "Log level set at {:?}. Messages will be shown according to it.",
-                    data.log_level
-                )
-                .as_str(),
-                format!("{:?}", data.log_level).to_lowercase().as_str(),
-                None,
-            );
-            log.debug(format!("{:#?}", data).as_str());
-            log.success("Config file validated correctly.");
-            Ok(())
-        }
-        Err(error) => Err(Error::from(error)),
+// The path goes to stdout so scripts can use it, the explanation to stderr
+fn show_location(location: &types::ConfigLocation) {
+    println!("{}", location.path.display());
+
+    let reason = match location.source {
+        types::ConfigSource::Argument => "It was given with --path.",
+        types::ConfigSource::Environment => "It was set in MIDIBOARD_CONFIG.",
+        types::ConfigSource::Search => "It's the first existing file in the search path.",
+        types::ConfigSource::Default => {
+            "No config file was found, this is where a new one is created."
+        }
+    };
+    eprintln!("{} Read as {:?}.", reason, location.format);
+
+    if !location.searched.is_empty() {
+        eprintln!("Searched, in order:");
+        for candidate in &location.searched {
+            let mark = if candidate == &location.path && candidate.is_file() {
+                "*"
+            } else {
+                " "
+            };
+            eprintln!(" {} {}", mark, candidate.display());
+        }
+    }
+}
+
+fn convert_config(
+    path: &Path,
+    format: types::ConfigFormat,
+    output: &Path,
+    target: types::ConfigFormat,
+) -> Result<(), Error> {
+    let log = Logger::new(types::LogLevel::Info);
+    if output.exists() {
+        return Err(Error::msg(format!(
+            "File already exists in path {:?}",
+            output
+        )));
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|error| Error::msg(format!("Cannot read {:?}: {}", path, error)))?;
+    let value = util::parse_value(&contents, format)?;
+    let converted = util::render_value(&value, target)?;
+
+    // Read the result back to make sure it says the same and is still a valid config
+    if util::parse_value(&converted, target)? != value {
+        return Err(Error::msg(format!(
+            "The config can't be written as {:?} without changing it, nothing was written.",
+            target
+        )));
+    }
+    util::parse_config(&converted, target)?;
+
+    fs::write(output, converted)?;
+    log.success(format!("Config written to {:?}.", output).as_str());
+    Ok(())
+}
+
+fn add_device(path: &Path, format: types::ConfigFormat) -> Result<(), Error> {
+    let log = Logger::new(types::LogLevel::Info);
+    let mut document = ConfigDocument::load(path, format)?;
+
+    let device = loop {
+        let device = prompts::ask_text(
+            "Device name, as listed by `midiboard devices --list`:",
+            None,
+        )?;
+        match document.device_index(&device) {
+            Ok(_) => println!("There is already an entry for {}.", device),
+            Err(_) => break device,
+        }
+    };
+    let virtual_port = prompts::ask_yes_no(
+        "Create it as a virtual port for other software to send events to?",
+        false,
+    )?;
+
+    let thresholds = loop {
+        let thresholds = json!({
+            "encoder": {
+                "activation": prompts::ask_number::<u64>("Encoder activation threshold (ms):", Some(300))?,
+                "detection": prompts::ask_number::<u64>("Encoder detection threshold (ms):", Some(40))?
+            },
+            "switch": {
+                "activation": prompts::ask_number::<u64>("Switch activation threshold (ms):", Some(400))?
+            },
+            "trigger": {
+                "activation": prompts::ask_number::<u64>("Trigger activation threshold (ms):", Some(1000))?
+            }
+        });
+        match prompts::check::<types::Thresholds>(&thresholds) {
+            Ok(()) => break thresholds,
+            Err(error) => println!("Invalid thresholds: {}", error),
+        }
+    };
+
+    let mut entry = json!({
+        "device": device,
+        "thresholds": thresholds,
+        "controls": {}
+    });
+    if virtual_port {
+        entry["virtual_port"] = json!(true);
+    }
+    prompts::check::<types::Config>(&entry)?;
+
+    let entries = document.value()["config"]
+        .as_array()
+        .map_or(0, |entries| entries.len());
+    document.set(
+        &[
+            Segment::Key(String::from("config")),
+            Segment::Index(entries),
+        ],
+        &entry,
+    )?;
+    document.save()?;
+
+    log.success(format!("Device {} added to {:?}.", device, path).as_str());
+    log.info(
+        format!(
+            "Add controls with `midiboard config add control --device \"{}\"` or `midiboard config learn --device \"{}\"`.",
+            device, device
+        )
+        .as_str(),
+    );
+    Ok(())
+}
+
+fn add_control(path: &Path, format: types::ConfigFormat, device: &str) -> Result<(), Error> {
+    let log = Logger::new(types::LogLevel::Info);
+    let mut document = ConfigDocument::load(path, format)?;
+    document.device_index(device)?;
+
+    let name = prompts::ask_control_name(&document, device, None)?;
+    let key = prompts::ask_number::<u8>(
+        "Key (0 to 127), as shown by `midiboard devices --input`:",
+        None,
+    )?;
+    let kind = prompts::ask_kind(None)?;
+    let command = prompts::ask_command(&kind)?;
+
+    let mut control = json!({ "key": key, "command": command });
+
+    if prompts::ask_yes_no("Override the device thresholds for this control?", false)? {
+        let mut threshold = json!({
+            "activation": prompts::ask_number::<u64>("Activation threshold (ms):", None)?
+        });
+        if kind == types::CommandKind::Encoder {
+            threshold["detection"] = json!(prompts::ask_number::<u64>(
+                "Detection threshold (ms):",
+                None
+            )?);
+        }
+        control["threshold"] = threshold;
+    }
+    prompts::check::<types::InputOption>(&control)?;
+
+    document.insert_control(device, &name, control)?;
+    document.save()?;
+
+    log.success(format!("Control {} added to {:?}.", name, path).as_str());
+    Ok(())
+}
+
+fn probe_devices(
+    documents: &[ConfigDocument],
+    probe: Probe,
+    log: Logger,
+) -> Result<Vec<validate::Diagnostic>, Error> {
+    let ports = devices::get_ports()?;
+    let mut diagnostics = Vec::new();
+    // Devices found connected, by the file and config index they come from
+    let mut connected: Vec<(usize, usize, String)> = Vec::new();
+
+    for (file, document) in documents.iter().enumerate() {
+        diagnostics.extend(validate::probe(document, &ports));
+        connected.extend(
+            validate::connected_devices(document, &ports)
+                .into_iter()
+                .map(|(index, name)| (file, index, name)),
+        );
+    }
+
+    // Only the devices found connected can be listened to
+    if let Some(seconds) = probe.listen {
+        log.info(
+            format!(
+                "Listening for {} seconds, move every configured control...",
+                seconds
+            )
+            .as_str(),
+        );
+        let names: Vec<String> = connected.iter().map(|(_, _, name)| name.clone()).collect();
+        let keys = devices::collect_keys(&names, Duration::from_secs(seconds))?;
+        let mut heard: Vec<HashMap<usize, HashSet<u8>>> = vec![HashMap::new(); documents.len()];
+        for ((file, index, _), keys) in connected.into_iter().zip(keys) {
+            heard[file].insert(index, keys);
+        }
+        for (document, heard) in documents.iter().zip(heard) {
+            diagnostics.extend(validate::unheard_keys(document, &heard));
+        }
+    }
+    Ok(diagnostics)
+}
+
+// JSON Schema of the config file, generated from the types it is read into
+pub fn schema() -> Value {
+    let generator = SchemaSettings::draft2019_09()
+        .with(|settings| {
+            // Optional fields are left out of the file, not set to null
+            settings.option_add_null_type = false;
+        })
+        .into_generator();
+    let mut schema = generator.into_root_schema_for::<types::ConfigFile>();
+    schema.schema.metadata().id = Some(String::from(SCHEMA_URL));
+    json!(schema)
+}
+
+fn get_value(path: &Path, format: types::ConfigFormat, key: &str) -> Result<(), Error> {
+    let document = ConfigDocument::load(path, format)?;
+    let value = document
+        .get(&document::parse_path(key)?)
+        .ok_or(Error::msg(format!("{} is not set in {:?}", key, path)))?;
+
+    // Strings are printed bare so they can be used directly from scripts
+    match value {
+        Value::String(text) => println!("{}", text),
+        other => println!("{}", serde_json::to_string_pretty(other)?),
+    }
+    Ok(())
+}
+
+fn set_value(
+    path: &Path,
+    format: types::ConfigFormat,
+    key: &str,
+    value: &str,
+    as_string: bool,
+) -> Result<(), Error> {
+    let mut document = ConfigDocument::load(path, format)?;
+
+    // Anything that isn't valid JSON is taken as a string, so `set ... Info` works unquoted
+    let value = if as_string {
+        Value::String(value.to_string())
+    } else {
+        serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
     };
+
+    document.set(&document::parse_path(key)?, &value)?;
+    document.save()
+}
+
+// Options for checking the config against the connected devices
+struct Probe {
+    // Seconds to listen for keys, if at all
+    listen: Option<u64>,
+}
+
+fn validate_config(
+    fullpath: &Path,
+    format: types::ConfigFormat,
+    probe: Option<Probe>,
+) -> Result<(), Error> {
+    let mut diagnostics = validate::check_file(fullpath, format)?;
+    let log = Logger::new(types::LogLevel::Info);
+
+    if let Some(probe) = probe {
+        // Probing needs files that at least parse
+        if let Ok(sources) = preprocess::collect(fullpath, format) {
+            let documents = sources
+                .iter()
+                .map(|source| ConfigDocument::load(&source.path, source.format))
+                .collect::<Result<Vec<ConfigDocument>, Error>>();
+            if let Ok(documents) = documents {
+                diagnostics.extend(probe_devices(&documents, probe, log)?);
+            }
+        }
+    }
+
+    let mut errors = 0;
+    for diagnostic in &diagnostics {
+        let message = format!("{}:{}", diagnostic.file.display(), diagnostic);
+        match diagnostic.severity {
+            validate::Severity::Error => {
+                errors += 1;
+                log.error(message.as_str())
+            }
+            validate::Severity::Warning => log.warn(message.as_str()),
+            validate::Severity::Info => log.info(message.as_str()),
+        }
+    }
+
+    let warnings = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == validate::Severity::Warning)
+        .count();
+    if errors > 0 {
+        return Err(Error::msg(format!(
+            "Found {} error(s) and {} warning(s) in {:?}.",
+            errors, warnings, fullpath
+        )));
+    }
+
+    let data = util::read_user_config(Some(&fullpath.display().to_string()), Some(&format))?;
+    let log = Logger::new(data.log_level);
+    log.dynamic(
+        format!(
+            "Log level set at {:?}. Messages will be shown according to it.",
+            data.log_level
+        )
+        .as_str(),
+        format!("{:?}", data.log_level).to_lowercase().as_str(),
+        None,
+    );
+    // The file as written, the config read from it has `${env:...}` and `${file:...}` filled in
+    log.debug(fs::read_to_string(fullpath)?.as_str());
+    log.success(
+        format!(
+            "Config file validated correctly, with {} warning(s).",
+            warnings
+        )
+        .as_str(),
+    );
+    Ok(())
 }
@@ -0,0 +1,182 @@
+use super::types::{ControlRequest, ControlResponse, InitialSwitchState};
+use super::util::Logger;
+use anyhow::Error;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+// A request waiting for the main thread, with where to send its answer
+pub type Pending = (ControlRequest, Sender<ControlResponse>);
+
+// Where the socket lives. The first of these wins:
+// 1. The `--socket` argument.
+// 2. `$XDG_RUNTIME_DIR/midiboard.sock`.
+// 3. `midiboard-$USER.sock` in the temporary directory.
+pub fn socket_path(cli: &clap::ArgMatches) -> PathBuf {
+    if let Some(path) = cli.get_one::<String>("socket") {
+        return PathBuf::from(path);
+    }
+    match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("midiboard.sock"),
+        None => env::temp_dir().join(format!(
+            "midiboard-{}.sock",
+            env::var("USER").unwrap_or_default()
+        )),
+    }
+}
+
+// Listens on the socket, passing every request to `requests`. Each connection gets its own
+// thread, and can send as many requests as it wants, one per line.
+pub fn listen(path: &Path, requests: Sender<Pending>, log: Logger) -> Result<(), Error> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        // Anything else at the path is not ours to remove
+        if !metadata.file_type().is_socket() {
+            return Err(Error::msg(format!(
+                "{} exists and is not a socket, choose another path with --socket",
+                path.display()
+            )));
+        }
+        // A socket nobody answers on is left over from a service that didn't stop cleanly
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::msg(format!(
+                "Another midiboard is already listening on {}",
+                path.display()
+            )));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    log.info(format!("Listening for commands on {}", path.display()).as_str());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let requests = requests.clone();
+                    thread::spawn(move || serve(stream, requests, log));
+                }
+                Err(error) => log.warn(format!("Control connection failed: {}", error).as_str()),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, requests: Sender<Pending>, log: Logger) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(error) => return log.warn(error.to_string().as_str()),
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        log.trace("Control request received", &line);
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => ask(&requests, request),
+            Err(error) => {
+                ControlResponse::failure(&Error::msg(format!("Invalid request: {}", error)))
+            }
+        };
+        let mut answer = serde_json::to_string(&response).unwrap_or_default();
+        answer.push('\n');
+        if writer.write_all(answer.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// Hands the request to the main thread and waits for its answer
+fn ask(requests: &Sender<Pending>, request: ControlRequest) -> ControlResponse {
+    let (reply, answer) = mpsc::channel();
+    if requests.send((request, reply)).is_err() {
+        return ControlResponse::failure(&Error::msg("The service is stopping."));
+    }
+    answer.recv().unwrap_or_else(|_| {
+        ControlResponse::failure(&Error::msg("The request was dropped without an answer."))
+    })
+}
+
+// Sends a single request to the running service and waits for its answer
+pub fn send(path: &Path, request: &ControlRequest) -> Result<ControlResponse, Error> {
+    let mut stream = UnixStream::connect(path).map_err(|error| {
+        Error::msg(format!(
+            "Could not connect to {}, is midiboard running? ({})",
+            path.display(),
+            error
+        ))
+    })?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    Ok(serde_json::from_str(&answer)?)
+}
+
+pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
+    let path = socket_path(cli);
+    let request = match cli.subcommand() {
+        Some(("devices", _)) => ControlRequest::Devices,
+        Some(("states", sub_m)) => ControlRequest::States {
+            device: sub_m.get_one::<String>("device").cloned(),
+        },
+        Some(("set", sub_m)) => ControlRequest::Set {
+            device: required(sub_m, "device")?,
+            control: required(sub_m, "control")?,
+            state: match required(sub_m, "state")?.eq_ignore_ascii_case("on") {
+                true => InitialSwitchState::ON,
+                false => InitialSwitchState::OFF,
+            },
+        },
+        Some(("fire", sub_m)) => ControlRequest::Fire {
+            device: required(sub_m, "device")?,
+            control: required(sub_m, "control")?,
+            value: sub_m.get_one::<u8>("value").copied(),
+        },
+        Some(("bank", sub_m)) => ControlRequest::Bank {
+            device: required(sub_m, "device")?,
+            bank: required(sub_m, "bank")?,
+        },
+        Some(("reload", _)) => ControlRequest::Reload,
+        Some(("log-level", sub_m)) => ControlRequest::LogLevel {
+            // Levels are accepted in any case, but named like `Debug` in the protocol
+            level: {
+                let level = required(sub_m, "level")?.to_lowercase();
+                let (first, rest) = level.split_at(1);
+                serde_json::from_value(serde_json::Value::String(first.to_uppercase() + rest))?
+            },
+        },
+        _ => return Ok(()),
+    };
+
+    let response = send(&path, &request)?;
+    if let Some(result) = &response.result {
+        println!("{}", serde_json::to_string_pretty(result)?);
+    }
+    match response.ok {
+        true => Ok(()),
+        false => Err(Error::msg(
+            response
+                .error
+                .unwrap_or_else(|| "The request failed.".to_string()),
+        )),
+    }
+}
+
+fn required(cli: &clap::ArgMatches, name: &str) -> Result<String, Error> {
+    cli.get_one::<String>(name)
+        .cloned()
+        .ok_or(Error::msg(format!("Missing {}", name)))
+}
@@ -1,8 +1,15 @@
-use super::types::LogLevel;
+use super::types::{
+    CommandKind, LogLevel, MessageFilter, MessageType, MidiMessage, PortDirection, PortInfo,
+    ValueRange,
+};
 use super::util;
 use anyhow::Error;
-use midir::{Ignore, MidiInput};
+use midir::{Ignore, MidiInput, MidiOutput};
+use serde_json::json;
+use std::collections::HashSet;
 use std::io::stdin;
+use std::thread;
+use std::time::Duration;
 
 pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
     let list = cli
@@ -11,7 +18,10 @@ pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
     let listen = cli.get_one::<String>("listen");
 
     if *list {
-        return list_devices();
+        let format = cli
+            .get_one::<String>("format")
+            .ok_or(Error::msg("Bad --format command."))?;
+        return list_devices(format);
     }
 
     if listen.is_some() {
@@ -19,38 +29,133 @@ pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
             .get_one::<String>("listen")
             .ok_or(Error::msg("No device name provided"))?
             .to_string();
-        return listen_to_device(device);
+        let types = cli
+            .get_many::<MessageType>("type")
+            .map(|types| types.copied().collect());
+        let channels = cli.get_one::<ValueRange>("channel").copied();
+        return listen_to_device(
+            device,
+            MessageFilter {
+                types,
+                channels,
+                keys: None,
+            },
+        );
     }
 
     panic!("No valid argument provided to the config subcommand.")
 }
 
-fn list_devices() -> Result<(), Error> {
-    let mut midi_in = MidiInput::new("midir reading input")?;
+pub fn get_ports() -> Result<Vec<PortInfo>, Error> {
+    let mut midi_in = MidiInput::new("Midiboard: Port Lister")?;
     midi_in.ignore(Ignore::None);
+    let midi_out = MidiOutput::new("Midiboard: Port Lister")?;
 
-    let log = util::Logger::new(LogLevel::Debug);
+    let mut ports = Vec::new();
+    for port in midi_in.ports().iter() {
+        ports.push(PortInfo::parse(
+            &midi_in.port_name(port)?,
+            PortDirection::Input,
+        ));
+    }
+    for port in midi_out.ports().iter() {
+        ports.push(PortInfo::parse(
+            &midi_out.port_name(port)?,
+            PortDirection::Output,
+        ));
+    }
+    Ok(ports)
+}
+
+// Listens to every device at once for `duration`, and returns the keys received from each
+pub fn collect_keys(devices: &[String], duration: Duration) -> Result<Vec<HashSet<u8>>, Error> {
+    let log = util::Logger::new(LogLevel::Warn);
+    let mut connections = Vec::new();
+
+    for device in devices {
+        let mut midi_in = MidiInput::new("Midiboard: Probe")?;
+        midi_in.ignore(Ignore::None);
+        let in_port = util::get_input_port(device, log)?;
+        let connection = midi_in
+            .connect(
+                &in_port,
+                "midiboard-probe",
+                |_, message, keys: &mut HashSet<u8>| {
+                    if let Some(number) =
+                        MidiMessage::parse(message).and_then(|decoded| decoded.number)
+                    {
+                        keys.insert(number);
+                    }
+                },
+                HashSet::new(),
+            )
+            .map_err(|error| Error::msg(error.to_string()))?;
+        connections.push(connection);
+    }
+
+    thread::sleep(duration);
+
+    Ok(connections
+        .into_iter()
+        .map(|connection| connection.close().1)
+        .collect())
+}
 
-    log.info("Currently connected devices:\n");
+fn list_devices(format: &str) -> Result<(), Error> {
+    let ports = get_ports()?;
 
-    let in_ports = midi_in.ports();
-    match in_ports.len() {
-        0 => return Err(Error::msg("No devices found.")),
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(&ports)?),
+        "tsv" => {
+            println!("direction\tclient_id\tport_id\tclient\tport\tname");
+            for port in ports {
+                println!(
+                    "{:?}\t{}\t{}\t{}\t{}\t{}",
+                    port.direction,
+                    port.client_id.map_or(String::new(), |id| id.to_string()),
+                    port.port_id.map_or(String::new(), |id| id.to_string()),
+                    port.client,
+                    port.port,
+                    port.name
+                );
+            }
+        }
         _ => {
-            for (_i, p) in in_ports.iter().enumerate() {
-                log.default(
-                    &midi_in
-                        .port_name(p)?
-                        .split_terminator(':')
-                        .collect::<Vec<&str>>()[0],
-                )
+            let log = util::Logger::new(LogLevel::Debug);
+
+            if ports.is_empty() {
+                return Err(Error::msg("No devices found."));
+            }
+
+            for direction in [PortDirection::Input, PortDirection::Output] {
+                log.info(format!("{:?} ports:\n", direction).as_str());
+                for port in ports.iter().filter(|port| port.direction == direction) {
+                    log.default(
+                        format!(
+                            "{} ({}) [{}:{}]",
+                            port.client,
+                            port.port,
+                            port.client_id
+                                .map_or(String::from("?"), |id| id.to_string()),
+                            port.port_id.map_or(String::from("?"), |id| id.to_string()),
+                        )
+                        .as_str(),
+                    )
+                }
             }
         }
     };
-    return Ok(());
+    Ok(())
+}
+
+// State kept by the listener callback, handed back when the connection closes
+struct Monitor {
+    filter: MessageFilter,
+    last_stamp: Option<u64>,
+    last_control: Option<MidiMessage>,
 }
 
-fn listen_to_device(device: String) -> Result<(), Error> {
+fn listen_to_device(device: String, filter: MessageFilter) -> Result<(), Error> {
     let log = util::Logger::new(LogLevel::Debug);
     let mut user_input = String::new();
     let mut midi_input = MidiInput::new("Midiboard: Device Listener")?;
@@ -61,17 +166,44 @@ fn listen_to_device(device: String) -> Result<(), Error> {
 
     log.info("Opening connection...");
 
-    let _conn = match midi_input.connect(
+    let monitor = Monitor {
+        filter,
+        last_stamp: None,
+        last_control: None,
+    };
+
+    let conn = match midi_input.connect(
         &in_port,
         "midir-read-input",
-        move |_stamp, message, _| {
+        move |stamp, message, monitor: &mut Monitor| {
             let closure_log = util::Logger::new(LogLevel::Debug);
-            closure_log.default(util::string_to_sstr(format!(
-                "key: {}, value: {}",
-                message[1], message[2]
-            )));
+            let delta = monitor
+                .last_stamp
+                .map_or(0.0, |last| stamp.saturating_sub(last) as f64 / 1000.0);
+            monitor.last_stamp = Some(stamp);
+
+            let decoded = match MidiMessage::parse(message) {
+                Some(decoded) => decoded,
+                None => {
+                    closure_log.default(
+                        format!("{:<16} +{:>10.3}ms  [{}]", "Unknown", delta, hex(message))
+                            .as_str(),
+                    );
+                    return;
+                }
+            };
+
+            if !monitor.filter.matches(&decoded) {
+                return;
+            }
+
+            if decoded.number.is_some() {
+                monitor.last_control = Some(decoded);
+            }
+
+            closure_log.default(describe_message(&decoded, message, delta).as_str());
         },
-        (),
+        monitor,
     ) {
         Ok(connect) => connect,
         Err(error) => return Err(Error::msg(error.to_string())),
@@ -87,6 +219,98 @@ fn listen_to_device(device: String) -> Result<(), Error> {
     user_input.clear();
     stdin().read_line(&mut user_input)?; // wait for next enter key press
 
+    let (_, monitor) = conn.close();
+
     log.info("Connection closed.");
+
+    if let Some(message) = monitor.last_control {
+        if let Some((name, control)) = control_snippet(&message, None) {
+            log.info("Snippet for the last touched control:\n");
+            println!("\"{}\": {}", name, serde_json::to_string_pretty(&control)?);
+        }
+    }
     Ok(())
 }
+
+fn hex(message: &[u8]) -> String {
+    message
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn describe_message(decoded: &MidiMessage, raw: &[u8], delta: f64) -> String {
+    let value = match (decoded.kind, raw) {
+        // Show the full 14 bit value, centered at 0
+        (MessageType::PitchBend, [_, lsb, msb, ..]) => {
+            Some(((*msb as i32) << 7 | *lsb as i32) - 8192)
+        }
+        _ => decoded.value.map(|value| value as i32),
+    };
+
+    format!(
+        "{:<16} ch {:<3} #{:<4} value {:<6} +{:>10.3}ms  [{}]",
+        format!("{:?}", decoded.kind),
+        decoded
+            .channel
+            .map_or(String::from("-"), |channel| channel.to_string()),
+        decoded
+            .number
+            .map_or(String::from("-"), |number| number.to_string()),
+        value.map_or(String::from("-"), |value| value.to_string()),
+        delta,
+        hex(raw)
+    )
+}
+
+// Builds a control entry for the config file out of a detected message, as `(name, control)`.
+// Without an explicit kind, notes are taken as triggers and controllers as encoders.
+pub fn control_snippet(
+    message: &MidiMessage,
+    kind: Option<CommandKind>,
+) -> Option<(String, serde_json::Value)> {
+    let key = message.number?;
+    let (prefix, default_kind) = match message.kind {
+        MessageType::NoteOn | MessageType::NoteOff | MessageType::PolyAftertouch => {
+            ("note", CommandKind::Trigger)
+        }
+        MessageType::ControlChange => ("cc", CommandKind::Encoder),
+        _ => return None,
+    };
+
+    let command = match kind.unwrap_or(default_kind) {
+        CommandKind::Encoder => json!({
+            "kind": "Encoder",
+            "execute": {
+                "cmd": "",
+                "args": ["@VALUE@"],
+                "replace": "@VALUE@",
+                "map_min": 0,
+                "map_max": 127
+            }
+        }),
+        CommandKind::Switch => json!({
+            "kind": "Switch",
+            "initial_state": "OFF",
+            "on": { "cmd": "", "args": [] },
+            "off": { "cmd": "", "args": [] }
+        }),
+        CommandKind::Trigger => json!({
+            "kind": "Trigger",
+            "execute": { "cmd": "", "args": [] }
+        }),
+        CommandKind::Cycle => json!({
+            "kind": "Cycle",
+            "states": [
+                { "name": "first", "execute": { "cmd": "", "args": [] } },
+                { "name": "second", "execute": { "cmd": "", "args": [] } }
+            ]
+        }),
+    };
+
+    Some((
+        format!("{}_{}", prefix, key),
+        json!({ "key": key, "command": command }),
+    ))
+}
@@ -0,0 +1,600 @@
+use super::preprocess;
+use super::types::ConfigFormat;
+use super::util;
+use anyhow::Error;
+use serde::Serialize;
+use serde_json::{ser::PrettyFormatter, Serializer, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Config file kept as its original text plus the position of every value in it.
+// Edits are spliced into the text, so everything that isn't touched keeps its exact
+// formatting, key order and unmodeled keys like `$schema`.
+// Files in other formats are edited as JSON and written back from the values, so only their
+// content is kept, not their comments or layout.
+#[derive(Debug, Clone)]
+pub struct ConfigDocument {
+    pub path: PathBuf,
+    pub format: ConfigFormat,
+    text: String,
+    tree: Spanned,
+    root: Value,
+    indent: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Byte range of a value in the text, and of its children if it's an object or an array
+#[derive(Debug, Clone)]
+pub struct Spanned {
+    pub start: usize,
+    pub end: usize,
+    pub node: Node,
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    Object(Vec<Member>),
+    Array(Vec<Spanned>),
+    Scalar,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub key: String,
+    pub key_start: usize,
+    pub value: Spanned,
+}
+
+impl ConfigDocument {
+    pub fn load(path: &Path, format: ConfigFormat) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| Error::msg(format!("Cannot read {:?}: {}", path, error)))?;
+        if format == ConfigFormat::Json {
+            return Self::parse(path, &contents);
+        }
+
+        let value = util::parse_value(&contents, format)
+            .map_err(|error| Error::msg(format!("Cannot parse {:?}: {}", path, error)))?;
+        let mut document = Self::parse(path, &util::render_value(&value, ConfigFormat::Json)?)?;
+        document.format = format;
+        Ok(document)
+    }
+
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, Error> {
+        let root: Value = serde_json::from_str(contents)
+            .map_err(|error| Error::msg(format!("Cannot parse {:?}: {}", path, error)))?;
+        let tree = Scanner::new(contents).document()?;
+
+        Ok(ConfigDocument {
+            path: path.to_path_buf(),
+            format: ConfigFormat::Json,
+            text: contents.to_string(),
+            tree,
+            root,
+            indent: detect_indent(contents),
+        })
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.root
+    }
+
+    pub fn locate(&self, path: &[Segment]) -> Option<&Spanned> {
+        let mut current = &self.tree;
+        for segment in path {
+            current = match (&current.node, segment) {
+                (Node::Object(members), Segment::Key(key)) => {
+                    &members.iter().find(|member| &member.key == key)?.value
+                }
+                (Node::Array(items), Segment::Index(index)) => items.get(*index)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    // 1-based line and column of a byte offset
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |index| index + 1) + 1;
+        (line, column)
+    }
+
+    // Where diagnostics about `path` should point: the key for object members, the value otherwise
+    pub fn position(&self, path: &[Segment]) -> Option<usize> {
+        let (last, parent_path) = match path.split_last() {
+            Some(split) => split,
+            None => return Some(self.tree.start),
+        };
+        match (&self.locate(parent_path)?.node, last) {
+            (Node::Object(members), Segment::Key(key)) => members
+                .iter()
+                .find(|member| &member.key == key)
+                .map(|member| member.key_start),
+            _ => self.locate(path).map(|value| value.start),
+        }
+    }
+
+    // Replaces the value at `path`, or adds it if its parent exists and it does not
+    pub fn set(&mut self, path: &[Segment], value: &Value) -> Result<(), Error> {
+        let (last, parent_path) = path
+            .split_last()
+            .ok_or(Error::msg("Cannot replace the whole document"))?;
+
+        if let Some(existing) = self.locate(path) {
+            let (start, end) = (existing.start, existing.end);
+            let rendered = self.render_value(value, &self.line_indent(start));
+            return self.splice(start, end, &rendered);
+        }
+
+        let parent = self
+            .locate(parent_path)
+            .ok_or(Error::msg(format!(
+                "{} does not exist",
+                format_path(parent_path)
+            )))?
+            .clone();
+
+        // Start of every child, the end of the last one, and what goes before the new value
+        let (starts, last_end, entry) = match (&parent.node, last) {
+            (Node::Object(members), Segment::Key(key)) => (
+                members
+                    .iter()
+                    .map(|member| member.key_start)
+                    .collect::<Vec<usize>>(),
+                members.last().map(|member| member.value.end),
+                format!("{}: ", serde_json::to_string(key)?),
+            ),
+            (Node::Array(items), Segment::Index(index)) if *index == items.len() => (
+                items.iter().map(|item| item.start).collect(),
+                items.last().map(|item| item.end),
+                String::new(),
+            ),
+            (Node::Array(_), Segment::Index(index)) => {
+                return Err(Error::msg(format!(
+                    "Index {} is out of bounds in {}",
+                    index,
+                    format_path(parent_path)
+                )))
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                    "{} cannot hold {}",
+                    format_path(parent_path),
+                    format_path(std::slice::from_ref(last))
+                )))
+            }
+        };
+
+        match (starts.first(), last_end) {
+            (Some(first), Some(last_end)) => {
+                // Keep entries on the same line if that's how the container was written
+                let multiline = self.text[parent.start..*first].contains('\n');
+                let child_indent = self.line_indent(*first);
+                let rendered = self.render_value(value, &child_indent);
+                let separator = if multiline {
+                    format!(",\n{}", child_indent)
+                } else {
+                    String::from(", ")
+                };
+                self.splice(
+                    last_end,
+                    last_end,
+                    &format!("{}{}{}", separator, entry, rendered),
+                )
+            }
+            _ => {
+                let parent_indent = self.line_indent(parent.start);
+                let child_indent = format!("{}{}", parent_indent, self.indent);
+                let rendered = self.render_value(value, &child_indent);
+                self.splice(
+                    parent.start + 1,
+                    parent.end - 1,
+                    &format!("\n{}{}{}\n{}", child_indent, entry, rendered, parent_indent),
+                )
+            }
+        }
+    }
+
+    pub fn remove(&mut self, path: &[Segment]) -> Result<(), Error> {
+        let (last, parent_path) = path
+            .split_last()
+            .ok_or(Error::msg("Cannot remove the whole document"))?;
+        let parent = self
+            .locate(parent_path)
+            .ok_or(Error::msg(format!("{} does not exist", format_path(path))))?
+            .clone();
+
+        // (start, end) of every entry, counting the key for object members
+        let (entries, position): (Vec<(usize, usize)>, Option<usize>) = match (&parent.node, last) {
+            (Node::Object(members), Segment::Key(key)) => (
+                members
+                    .iter()
+                    .map(|member| (member.key_start, member.value.end))
+                    .collect(),
+                members.iter().position(|member| &member.key == key),
+            ),
+            (Node::Array(items), Segment::Index(index)) => (
+                items.iter().map(|item| (item.start, item.end)).collect(),
+                Some(*index).filter(|index| *index < items.len()),
+            ),
+            _ => (Vec::new(), None),
+        };
+        let position =
+            position.ok_or(Error::msg(format!("{} does not exist", format_path(path))))?;
+
+        let (start, end) = entries[position];
+        if position > 0 {
+            // Take the separator before the entry along with it
+            self.splice(entries[position - 1].1, end, "")
+        } else if let Some((next_start, _)) = entries.get(1) {
+            self.splice(start, *next_start, "")
+        } else {
+            self.splice(parent.start + 1, parent.end - 1, "")
+        }
+    }
+
+    pub fn get(&self, path: &[Segment]) -> Option<&Value> {
+        let mut current = &self.root;
+        for segment in path {
+            current = match segment {
+                Segment::Key(key) => current.as_object()?.get(key)?,
+                Segment::Index(index) => current.as_array()?.get(*index)?,
+            };
+        }
+        Some(current)
+    }
+
+    pub fn device_index(&self, device: &str) -> Result<usize, Error> {
+        // Devices are matched the same way ports are, ignoring case and spaces
+        let wanted = device.to_lowercase().replace(' ', "");
+        self.root["config"]
+            .as_array()
+            .ok_or(Error::msg("The config file has no `config` array"))?
+            .iter()
+            .position(|entry| {
+                entry["device"]
+                    .as_str()
+                    .is_some_and(|name| name.to_lowercase().replace(' ', "") == wanted)
+            })
+            .ok_or(Error::msg(format!(
+                "Device {} not found in the config file",
+                device
+            )))
+    }
+
+    pub fn has_control(&self, device: &str, name: &str) -> Result<bool, Error> {
+        let index = self.device_index(device)?;
+        Ok(self.root["config"][index]["controls"].get(name).is_some())
+    }
+
+    pub fn insert_control(
+        &mut self,
+        device: &str,
+        name: &str,
+        control: Value,
+    ) -> Result<(), Error> {
+        let index = self.device_index(device)?;
+
+        if self.has_control(device, name)? {
+            return Err(Error::msg(format!(
+                "Control {} already exists for device {}",
+                name, device
+            )));
+        }
+
+        if let (Some(key), Some(controls)) = (
+            control.get("key"),
+            self.root["config"][index]["controls"].as_object(),
+        ) {
+            if let Some((other, _)) = controls
+                .iter()
+                .find(|(_, existing)| existing.get("key") == Some(key))
+            {
+                return Err(Error::msg(format!(
+                    "Key {} is already used by control {} of device {}",
+                    key, other, device
+                )));
+            }
+        }
+
+        let mut path = vec![
+            Segment::Key(String::from("config")),
+            Segment::Index(index),
+            Segment::Key(String::from("controls")),
+        ];
+        if self.locate(&path).is_none() {
+            return self.set(&path, &serde_json::json!({ name: control }));
+        }
+        path.push(Segment::Key(name.to_string()));
+        self.set(&path, &control)
+    }
+
+    pub fn remove_control(&mut self, device: &str, name: &str) -> Result<(), Error> {
+        let index = self.device_index(device)?;
+        if !self.has_control(device, name)? {
+            return Err(Error::msg(format!(
+                "Control {} not found for device {}",
+                name, device
+            )));
+        }
+        self.remove(&[
+            Segment::Key(String::from("config")),
+            Segment::Index(index),
+            Segment::Key(String::from("controls")),
+            Segment::Key(name.to_string()),
+        ])
+    }
+
+    pub fn remove_device(&mut self, device: &str) -> Result<(), Error> {
+        let index = self.device_index(device)?;
+        self.remove(&[Segment::Key(String::from("config")), Segment::Index(index)])
+    }
+
+    // Validates the edited document against the config types, then replaces the file atomically
+    pub fn save(&self) -> Result<(), Error> {
+        // Checked as it will be read, with its includes and named commands resolved
+        preprocess::resolve(&self.path, self.format, self.root.clone())
+            .and_then(|resolved| {
+                util::parse_config(&resolved.to_string(), ConfigFormat::Json).map_err(Error::from)
+            })
+            .map_err(|error| {
+                Error::msg(format!(
+                    "The edited config would not be valid, nothing was written: {}",
+                    error
+                ))
+            })?;
+
+        let file_name = self
+            .path
+            .file_name()
+            .ok_or(Error::msg("Config path does not point to a file"))?
+            .to_string_lossy();
+        let temp_path = self.path.with_file_name(format!(".{}.tmp", file_name));
+
+        let contents = match self.format {
+            ConfigFormat::Json => self.text.clone(),
+            format => util::render_value(&self.root, format)?,
+        };
+        fs::write(&temp_path, contents)?;
+        if let Err(error) = fs::rename(&temp_path, &self.path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(Error::from(error));
+        }
+        Ok(())
+    }
+
+    fn splice(&mut self, start: usize, end: usize, replacement: &str) -> Result<(), Error> {
+        let mut text = self.text.clone();
+        text.replace_range(start..end, replacement);
+        let edited = Self::parse(&self.path, &text)?;
+        self.text = edited.text;
+        self.tree = edited.tree;
+        self.root = edited.root;
+        Ok(())
+    }
+
+    fn line_indent(&self, offset: usize) -> String {
+        let line_start = self.text[..offset].rfind('\n').map_or(0, |index| index + 1);
+        let line = &self.text[line_start..];
+        line[..line.len() - line.trim_start().len()].to_string()
+    }
+
+    fn render_value(&self, value: &Value, base_indent: &str) -> String {
+        let mut buffer = Vec::new();
+        let formatter = PrettyFormatter::with_indent(self.indent.as_bytes());
+        let mut serializer = Serializer::with_formatter(&mut buffer, formatter);
+        // Writing a Value into a Vec cannot fail
+        value.serialize(&mut serializer).unwrap_or_default();
+        String::from_utf8_lossy(&buffer).replace('\n', &format!("\n{}", base_indent))
+    }
+}
+
+pub fn format_path(path: &[Segment]) -> String {
+    let mut formatted = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) => {
+                if !formatted.is_empty() {
+                    formatted.push('.');
+                }
+                formatted.push_str(key);
+            }
+            Segment::Index(index) => formatted.push_str(&format!("[{}]", index)),
+        }
+    }
+    if formatted.is_empty() {
+        formatted.push_str("(root)");
+    }
+    formatted
+}
+
+// Parses paths like `config[0].controls.volume.key`
+pub fn parse_path(path: &str) -> Result<Vec<Segment>, Error> {
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, indexes) = part.split_once('[').unwrap_or((part, ""));
+        if key.is_empty() && indexes.is_empty() {
+            return Err(Error::msg(format!("Empty segment in path {}", path)));
+        }
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_string()));
+        }
+        if !indexes.is_empty() {
+            for index in indexes.trim_end_matches(']').split("][") {
+                segments.push(Segment::Index(index.parse().map_err(|_| {
+                    Error::msg(format!("Invalid index {} in path {}", index, path))
+                })?));
+            }
+        }
+    }
+    Ok(segments)
+}
+
+// Takes the indentation of the first indented line, falling back to 4 spaces like the skeleton
+fn detect_indent(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|indent| !indent.is_empty())
+        .unwrap_or("    ")
+        .to_string()
+}
+
+// Same rule as the schema: lowercase, digits and low dashes, starting with a letter
+pub fn is_valid_control_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() > 1
+        && matches!(chars.next(), Some(first) if first.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+// Minimal JSON reader that only records where things are.
+// It runs after serde_json accepted the text, so it doesn't try to report syntax errors nicely.
+struct Scanner<'a> {
+    text: &'a str,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(text: &'a str) -> Self {
+        Scanner {
+            text,
+            bytes: text.as_bytes(),
+            position: 0,
+        }
+    }
+
+    fn document(mut self) -> Result<Spanned, Error> {
+        self.value()
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        while self
+            .bytes
+            .get(self.position)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+        self.bytes.get(self.position).copied()
+    }
+
+    fn value(&mut self) -> Result<Spanned, Error> {
+        let node = match self.peek() {
+            Some(b'{') => return self.object(),
+            Some(b'[') => return self.array(),
+            Some(b'"') => {
+                let start = self.position;
+                self.string()?;
+                return Ok(Spanned {
+                    start,
+                    end: self.position,
+                    node: Node::Scalar,
+                });
+            }
+            Some(_) => Node::Scalar,
+            None => return Err(Error::msg("Unexpected end of the document")),
+        };
+        let start = self.position;
+        while self
+            .bytes
+            .get(self.position)
+            .is_some_and(|byte| !b",]} \t\r\n".contains(byte))
+        {
+            self.position += 1;
+        }
+        Ok(Spanned {
+            start,
+            end: self.position,
+            node,
+        })
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let start = self.position;
+        self.position += 1;
+        while let Some(byte) = self.bytes.get(self.position) {
+            match byte {
+                b'\\' => self.position += 2,
+                b'"' => {
+                    self.position += 1;
+                    return Ok(serde_json::from_str(&self.text[start..self.position])?);
+                }
+                _ => self.position += 1,
+            }
+        }
+        Err(Error::msg("Unterminated string"))
+    }
+
+    fn object(&mut self) -> Result<Spanned, Error> {
+        let start = self.position;
+        self.position += 1;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+        } else {
+            loop {
+                self.peek();
+                let key_start = self.position;
+                let key = self.string()?;
+                if self.peek() != Some(b':') {
+                    return Err(Error::msg(format!("Malformed object at byte {}", start)));
+                }
+                self.position += 1;
+                let value = self.value()?;
+                members.push(Member {
+                    key,
+                    key_start,
+                    value,
+                });
+                match self.peek() {
+                    Some(b',') => self.position += 1,
+                    Some(b'}') => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => return Err(Error::msg(format!("Malformed object at byte {}", start))),
+                }
+            }
+        }
+        Ok(Spanned {
+            start,
+            end: self.position,
+            node: Node::Object(members),
+        })
+    }
+
+    fn array(&mut self) -> Result<Spanned, Error> {
+        let start = self.position;
+        self.position += 1;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+        } else {
+            loop {
+                items.push(self.value()?);
+                match self.peek() {
+                    Some(b',') => self.position += 1,
+                    Some(b']') => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => return Err(Error::msg(format!("Malformed array at byte {}", start))),
+                }
+            }
+        }
+        Ok(Spanned {
+            start,
+            end: self.position,
+            node: Node::Array(items),
+        })
+    }
+}
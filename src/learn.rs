@@ -0,0 +1,187 @@
+use super::document::ConfigDocument;
+use super::prompts;
+use super::types::{CommandKind, LogLevel, MessageType, MidiMessage};
+use super::util::{self, Logger};
+use anyhow::Error;
+use midir::{Ignore, MidiInput};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::stdin;
+
+// A button held down longer than this (in microseconds) is taken as a switch rather than a trigger
+const HOLD_THRESHOLD: u64 = 500_000;
+
+// Values sent by the usual relative encoder modes (two's complement, binary offset and sign bit)
+const RELATIVE_VALUES: [u8; 12] = [1, 2, 3, 61, 62, 63, 65, 66, 67, 125, 126, 127];
+
+// Messages recorded while learning, with their timestamp in microseconds
+pub type Recording = Vec<(u64, MidiMessage)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guess {
+    pub kind: CommandKind,
+    pub relative: bool,
+}
+
+pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
+    let log = Logger::new(LogLevel::Info);
+    let device = cli
+        .get_one::<String>("device")
+        .ok_or(Error::msg("No device name provided"))?;
+    let (path, format) = util::config_location(cli)?;
+
+    let mut document = ConfigDocument::load(&path, format)?;
+    // Fail before listening if the device is not in the file
+    document.device_index(device)?;
+
+    let events = record(device, log)?;
+    let (message, guess) = detect(&events).ok_or(Error::msg(
+        "No note or controller was detected. Nothing was written.",
+    ))?;
+    let key = message
+        .number
+        .ok_or(Error::msg("Detected message carries no key"))?;
+
+    log.success(
+        format!(
+            "Detected {:?} #{} on channel {}, looks like {} {:?}.",
+            message.kind,
+            key,
+            message.channel.unwrap_or_default(),
+            if guess.relative { "a relative" } else { "a" },
+            guess.kind
+        )
+        .as_str(),
+    );
+
+    if guess.relative {
+        log.warn("Relative encoders send steps instead of positions, mapped values will not follow the knob position.");
+    }
+
+    let kind = prompts::ask_kind(Some(&guess.kind))?;
+    let default_name = match message.kind {
+        MessageType::ControlChange => format!("cc_{}", key),
+        _ => format!("note_{}", key),
+    };
+    let name = prompts::ask_control_name(&document, device, Some(&default_name))?;
+    let command = prompts::ask_command(&kind)?;
+
+    document.insert_control(device, &name, json!({ "key": key, "command": command }))?;
+    document.save()?;
+
+    log.success(format!("Control {} added to {:?}.", name, path).as_str());
+    Ok(())
+}
+
+fn record(device: &str, log: Logger) -> Result<Recording, Error> {
+    let mut midi_input = MidiInput::new("Midiboard: Learn")?;
+    midi_input.ignore(Ignore::None);
+
+    let in_port = util::get_input_port(device, log)?;
+
+    let conn = midi_input
+        .connect(
+            &in_port,
+            "midiboard-learn",
+            |stamp, message, events: &mut Recording| {
+                if let Some(decoded) = MidiMessage::parse(message) {
+                    events.push((stamp, decoded));
+                }
+            },
+            Vec::new(),
+        )
+        .map_err(|error| Error::msg(error.to_string()))?;
+
+    log.info("Move the control to learn. Hold buttons down for a while to use them as a switch, sweep knobs and faders.");
+    log.info("Press Enter when done.\n");
+
+    let mut user_input = String::new();
+    stdin().read_line(&mut user_input)?;
+
+    let (_, events) = conn.close();
+    Ok(events)
+}
+
+// Picks the control that sent the most messages and guesses its kind
+pub fn detect(events: &[(u64, MidiMessage)]) -> Option<(MidiMessage, Guess)> {
+    // Grouped by (is a note, channel, number)
+    let mut groups: HashMap<(bool, Option<u8>, u8), Recording> = HashMap::new();
+
+    for (stamp, message) in events {
+        let is_note = match message.kind {
+            MessageType::NoteOn | MessageType::NoteOff | MessageType::PolyAftertouch => true,
+            MessageType::ControlChange => false,
+            _ => continue,
+        };
+        if let Some(number) = message.number {
+            groups
+                .entry((is_note, message.channel, number))
+                .or_default()
+                .push((*stamp, *message));
+        }
+    }
+
+    let group = groups.into_values().max_by_key(|group| group.len())?;
+    let first = group.first()?.1;
+    Some((first, guess_kind(&group)))
+}
+
+pub fn guess_kind(events: &[(u64, MidiMessage)]) -> Guess {
+    let values: Vec<u8> = events
+        .iter()
+        .filter_map(|(_, message)| message.value)
+        .collect();
+    let is_note = events
+        .iter()
+        .any(|(_, message)| message.kind != MessageType::ControlChange);
+
+    if !is_note {
+        let mut distinct = values.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+
+        if values.len() >= 3
+            && distinct.len() <= 4
+            && values.iter().all(|value| RELATIVE_VALUES.contains(value))
+        {
+            return Guess {
+                kind: CommandKind::Encoder,
+                relative: true,
+            };
+        }
+
+        let is_button =
+            values.contains(&0) && values.iter().all(|value| *value == 0 || *value == 127);
+        if !is_button {
+            return Guess {
+                kind: CommandKind::Encoder,
+                relative: false,
+            };
+        }
+    }
+
+    // Longest time between a press and its release
+    let mut pressed: Option<u64> = None;
+    let mut longest_hold = 0;
+    for (stamp, message) in events {
+        let is_press = match message.kind {
+            MessageType::NoteOn => true,
+            MessageType::ControlChange => message.value.unwrap_or_default() > 0,
+            _ => false,
+        };
+        if is_press {
+            pressed.get_or_insert(*stamp);
+        } else if let Some(start) = pressed.take() {
+            longest_hold = longest_hold.max(stamp.saturating_sub(start));
+        }
+    }
+
+    Guess {
+        kind: if longest_hold >= HOLD_THRESHOLD {
+            CommandKind::Switch
+        } else {
+            CommandKind::Trigger
+        },
+        relative: false,
+    }
+}
@@ -1,17 +1,32 @@
 use anyhow::Error;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use colored::*;
 
 mod config;
+mod control;
 mod devices;
+mod document;
+mod learn;
+mod preprocess;
+mod prompts;
 mod run;
+mod state;
 #[cfg(test)]
 mod tests;
 mod types;
 mod util;
+mod validate;
 
 fn main() {
-    let matches = Command::new("midiboard")
+    let runtime = run(cli().get_matches());
+    match runtime {
+        Ok(()) => {}
+        Err(error) => util::Logger::fatal(Default::default(), &error.to_string()),
+    }
+}
+
+pub fn cli() -> Command {
+    Command::new("midiboard")
         .version("0.3.3")
         .author(util::string_to_sstr(format!("{}", "Agata Ordano - aordano@protonmail.com".bright_cyan())))
         .about("Utility that allows using an arbitrary MIDI controller as a control board.")
@@ -41,6 +56,17 @@ fn main() {
                 .help("Lists active MIDI devices and outputs them to stdout.")
                 .conflicts_with("listen")
             )
+            .arg(
+                Arg::new("format")
+                .short('f')
+                .long("format")
+                .num_args(1)
+                .value_name("FORMAT")
+                .value_parser(["text", "json", "tsv"])
+                .default_value("text")
+                .requires("list")
+                .help("Output format for the device list. Use json or tsv to consume it from scripts.")
+            )
             .arg(
                 Arg::new("listen")
                 .alias("input")
@@ -51,6 +77,27 @@ fn main() {
                 .conflicts_with("list")
                 .help("Listens to the given MIDI device and outputs all events to stdout.")
             )
+            .arg(
+                Arg::new("type")
+                .short('t')
+                .long("type")
+                .num_args(1)
+                .action(ArgAction::Append)
+                .value_name("MESSAGE TYPE")
+                .value_parser(clap::value_parser!(types::MessageType))
+                .requires("listen")
+                .help("Only shows messages of this type (NoteOn, NoteOff, ControlChange, PitchBend...). Can be repeated.")
+            )
+            .arg(
+                Arg::new("channel")
+                .short('c')
+                .long("channel")
+                .num_args(1)
+                .value_name("CHANNEL")
+                .value_parser(types::ValueRange::channels)
+                .requires("listen")
+                .help("Only shows messages on this channel (1 to 16) or range of channels, like 1-9.")
+            )
         )
         .subcommand(
             Command::new("config")
@@ -58,7 +105,7 @@ fn main() {
             .about("Manages the configuration file.")
             .long_about(util::string_to_sstr(
                 format!("{}\n {} {} {}", 
-                    "This command allows you to generate a skeleton for the config file, or devices validity of an existing one.".yellow(), 
+                    "This command allows you to create, edit and validate the config file.".yellow(), 
                     "By default the configuration file will be generated and read from ", 
                     "$HOME".bright_purple(),
                     concat!(", but you can select an alternative path if desired.")
@@ -72,17 +119,6 @@ fn main() {
                 .long("validate")
                 .num_args(0)
                 .help("Validates the config file.")
-                .conflicts_with("generate")
-            )
-            .arg(
-                Arg::new("generate")
-                .short('g')
-                .long("generate")
-                .alias("skeleton")
-                .alias("blueprint")
-                .num_args(0)
-                .conflicts_with("validate")
-                .help("Generates a skeleton config file.")
             )
             .arg(
                 Arg::new("path")
@@ -91,8 +127,251 @@ fn main() {
                 .value_name("CONFIG FILE")
                 .alias("file")
                 .num_args(1)
+                .global(true)
                 .help("Selects a custom path for the config file.")
             )
+            .arg(
+                Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_name("FORMAT")
+                .num_args(1)
+                .global(true)
+                .value_parser(clap::value_parser!(types::ConfigFormat))
+                .help("Format of the config file: json, yaml, toml or ron. By default it's taken from the file extension.")
+            )
+            .subcommand(
+                Command::new("new")
+                .alias("generate")
+                .about("Creates a new config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Creates a new config file, with no devices in it.".yellow(),
+                        "Use --skeleton to get an example device with a couple of controls instead, to edit it by hand."
+                    )
+                ))
+                .arg(
+                    Arg::new("skeleton")
+                    .short('s')
+                    .long("skeleton")
+                    .alias("blueprint")
+                    .num_args(0)
+                    .help("Fills the new file with an example device instead of leaving it empty.")
+                )
+            )
+            .subcommand(
+                Command::new("validate")
+                .about("Validates the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Checks the config file and reports every problem found with its line and column.".yellow(),
+                        "Besides the format, it checks for repeated keys, inconsistent value mappings and thresholds, commands missing from $PATH and unknown fields. Warnings don't make the check fail."
+                    )
+                ))
+                .arg(
+                    Arg::new("probe")
+                    .long("probe")
+                    .num_args(0)
+                    .help("Also checks that the configured devices are connected, suggesting close names for the missing ones.")
+                )
+                .arg(
+                    Arg::new("listen")
+                    .long("listen")
+                    .num_args(1)
+                    .value_name("SECONDS")
+                    .value_parser(clap::value_parser!(u64))
+                    .requires("probe")
+                    .help("With --probe, listens to the connected devices and flags the configured keys that were not received.")
+                )
+            )
+            .subcommand(
+                Command::new("path")
+                .about("Shows which config file is used, and why.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Prints the path of the config file that the other commands use, and explains why it was picked.".yellow(),
+                        "The first of these wins: --path, the MIDIBOARD_CONFIG environment variable, the first existing file in $XDG_CONFIG_HOME/midiboard/, $HOME and /etc/midiboard/, or $HOME/midiboard.json."
+                    )
+                ))
+            )
+            .subcommand(
+                Command::new("schema")
+                .about("Prints the JSON Schema of the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Prints the JSON Schema of the config file, generated from the same types the config is read into.".yellow(),
+                        "Editors can use it to lint and document the config file."
+                    )
+                ))
+            )
+            .subcommand(
+                Command::new("convert")
+                .about("Translates the config file to another format.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Writes the config file in another format, next to the original unless an output is given.".yellow(),
+                        "The result is read back and compared with the original before finishing, so nothing is lost on the way. Comments are not carried over."
+                    )
+                ))
+                .arg(
+                    Arg::new("to")
+                    .short('t')
+                    .long("to")
+                    .value_name("FORMAT")
+                    .num_args(1)
+                    .required(true)
+                    .value_parser(clap::value_parser!(types::ConfigFormat))
+                    .help("Format to translate to: json, yaml, toml or ron.")
+                )
+                .arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .num_args(1)
+                    .help("Where to write the result. By default, the config file path with the extension of the new format.")
+                )
+            )
+            .subcommand(
+                Command::new("get")
+                .about("Prints a value of the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Prints the value found at KEY, like `config[0].controls.volume.key`.".yellow(),
+                        "Strings are printed as they are, anything else as JSON."
+                    )
+                ))
+                .arg(
+                    Arg::new("key")
+                    .value_name("KEY")
+                    .required(true)
+                    .help("Path to the value, with dots between keys and brackets for indexes.")
+                )
+            )
+            .subcommand(
+                Command::new("set")
+                .about("Sets a value of the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Replaces the value at KEY, or adds it if it does not exist yet but its parent does.".yellow(),
+                        "VALUE is read as JSON, and taken as a string if it isn't valid JSON. The file is only written if it is still valid after the change."
+                    )
+                ))
+                .arg(
+                    Arg::new("key")
+                    .value_name("KEY")
+                    .required(true)
+                    .help("Path to the value, with dots between keys and brackets for indexes.")
+                )
+                .arg(
+                    Arg::new("value")
+                    .value_name("VALUE")
+                    .required(true)
+                    .allow_hyphen_values(true)
+                    .help("New value, as JSON.")
+                )
+                .arg(
+                    Arg::new("string")
+                    .short('s')
+                    .long("string")
+                    .num_args(0)
+                    .help("Takes VALUE as a string even if it is valid JSON.")
+                )
+            )
+            .subcommand(
+                Command::new("remove")
+                .about("Removes a value, a control or a device from the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Removes the value at KEY, or a whole control or device by name.".yellow(),
+                        "The file is only written if it is still valid after the change."
+                    )
+                ))
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("key")
+                    .value_name("KEY")
+                    .help("Path to the value, with dots between keys and brackets for indexes.")
+                )
+                .subcommand(
+                    Command::new("control")
+                    .about("Removes a control from a device.")
+                    .arg(
+                        Arg::new("name")
+                        .value_name("NAME")
+                        .required(true)
+                        .help("Name of the control.")
+                    )
+                    .arg(
+                        Arg::new("device")
+                        .short('d')
+                        .long("device")
+                        .num_args(1)
+                        .value_name("DEVICE")
+                        .required(true)
+                        .help("Device the control belongs to.")
+                    )
+                )
+                .subcommand(
+                    Command::new("device")
+                    .about("Removes a device entry and all its controls.")
+                    .arg(
+                        Arg::new("name")
+                        .value_name("NAME")
+                        .required(true)
+                        .help("Name of the device.")
+                    )
+                )
+            )
+            .subcommand(
+                Command::new("add")
+                .about("Interactively adds a device or a control to the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Asks for every field of a new device or control and inserts it in the config file.".yellow(),
+                        "Each answer is checked as it is entered, and the rest of the file is kept as it is."
+                    )
+                ))
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("device")
+                    .about("Adds a new device entry to the config file.")
+                )
+                .subcommand(
+                    Command::new("control")
+                    .about("Adds a new control to a device of the config file.")
+                    .arg(
+                        Arg::new("device")
+                        .short('d')
+                        .long("device")
+                        .num_args(1)
+                        .value_name("DEVICE")
+                        .required(true)
+                        .help("Device to add the control to. It must already have an entry in the config file.")
+                    )
+                )
+            )
+            .subcommand(
+                Command::new("learn")
+                .about("Listens to a device and adds the moved control to the config file.")
+                .long_about(util::string_to_sstr(
+                    format!("{}\n {}",
+                        "Detects which control of the device was moved and inserts it as a new control in the config file.".yellow(),
+                        concat!("The kind of control is guessed from the messages received: held buttons suggest a Switch, quick presses a Trigger, ",
+                        "and sweeps of a knob or fader an Encoder. The name and commands are asked for interactively, and the rest of the file is kept as it is.")
+                    )
+                ))
+                .arg(
+                    Arg::new("device")
+                    .short('d')
+                    .long("device")
+                    .num_args(1)
+                    .value_name("DEVICE")
+                    .required(true)
+                    .help("Device to listen to. It must already have an entry in the config file.")
+                )
+            )
         )
         .subcommand(
             Command::new("run")
@@ -115,14 +394,160 @@ fn main() {
                 .num_args(1)
                 .help("Selects a custom path for the config file.")
             )
+            .arg(
+                Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_name("FORMAT")
+                .num_args(1)
+                .value_parser(clap::value_parser!(types::ConfigFormat))
+                .help("Format of the config file: json, yaml, toml or ron. By default it's taken from the file extension.")
+            )
+            .arg(
+                Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .num_args(1)
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("5")
+                .help("When stopping, how long to wait for running commands before stopping them.")
+            )
+            .arg(
+                Arg::new("socket")
+                .long("socket")
+                .num_args(1)
+                .value_name("PATH")
+                .help("Where to listen for `ctl` commands. By default $XDG_RUNTIME_DIR/midiboard.sock.")
+            )
+            .arg(
+                Arg::new("state-file")
+                .long("state-file")
+                .num_args(1)
+                .value_name("PATH")
+                .help("Keeps switch states and encoder values in this file between runs. Used at $XDG_STATE_HOME/midiboard/state.json when a switch has initial_state restore.")
+            )
             .arg_required_else_help(false)
         )
-        .get_matches();
-    let runtime = run(matches);
-    match runtime {
-        Ok(()) => {}
-        Err(error) => util::Logger::fatal(Default::default(), &error.to_string()),
-    }
+        .subcommand(
+            Command::new("ctl")
+                .alias("control")
+            .about("Controls the running service.")
+            .long_about(util::string_to_sstr(
+                format!("{}\n {}",
+                    "Talks to a running `midiboard run` through its control socket.".yellow(),
+                    "It can list the devices, show and set switches, fire controls, switch banks, reload the config and change the log level without restarting. Results are printed as JSON."
+                )
+            ))
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .arg(
+                Arg::new("socket")
+                .long("socket")
+                .num_args(1)
+                .value_name("PATH")
+                .global(true)
+                .help("Socket of the running service. By default $XDG_RUNTIME_DIR/midiboard.sock.")
+            )
+            .subcommand(
+                Command::new("devices")
+                .about("Lists the configured devices and whether they are connected.")
+            )
+            .subcommand(
+                Command::new("states")
+                .about("Shows whether each switch is ON or OFF, and the state of each cycle.")
+                .arg(
+                    Arg::new("device")
+                    .short('d')
+                    .long("device")
+                    .num_args(1)
+                    .value_name("DEVICE")
+                    .help("Only shows the switches of this device.")
+                )
+            )
+            .subcommand(
+                Command::new("set")
+                .about("Sets a switch to ON or OFF without running its commands.")
+                .arg(
+                    Arg::new("device")
+                    .value_name("DEVICE")
+                    .required(true)
+                    .help("Device the switch belongs to.")
+                )
+                .arg(
+                    Arg::new("control")
+                    .value_name("CONTROL")
+                    .required(true)
+                    .help("Name of the switch.")
+                )
+                .arg(
+                    Arg::new("state")
+                    .value_name("STATE")
+                    .required(true)
+                    .ignore_case(true)
+                    .value_parser(["ON", "OFF"])
+                    .help("State to leave the switch in.")
+                )
+            )
+            .subcommand(
+                Command::new("fire")
+                .about("Runs a control as if it was pressed.")
+                .long_about("Runs the commands of a control as if it was pressed. Switches toggle, cycles step forward, and encoders run with the given value.")
+                .arg(
+                    Arg::new("device")
+                    .value_name("DEVICE")
+                    .required(true)
+                    .help("Device the control belongs to.")
+                )
+                .arg(
+                    Arg::new("control")
+                    .value_name("CONTROL")
+                    .required(true)
+                    .help("Name of the control.")
+                )
+                .arg(
+                    Arg::new("value")
+                    .short('v')
+                    .long("value")
+                    .num_args(1)
+                    .value_name("VALUE")
+                    .value_parser(clap::value_parser!(u8).range(0..128))
+                    .help("Value to run the command with, from 0 to 127. Required for encoders.")
+                )
+            )
+            .subcommand(
+                Command::new("bank")
+                .about("Switches a device to another bank of controls.")
+                .long_about("Switches a device to another of its `banks`, so its keys run the controls of that bank. Controls outside the banks keep their state.")
+                .arg(
+                    Arg::new("device")
+                    .value_name("DEVICE")
+                    .required(true)
+                    .help("Device to switch.")
+                )
+                .arg(
+                    Arg::new("bank")
+                    .value_name("BANK")
+                    .required(true)
+                    .help("Name of the bank to make active.")
+                )
+            )
+            .subcommand(
+                Command::new("reload")
+                .about("Reloads the config, reconnecting only the devices that changed.")
+            )
+            .subcommand(
+                Command::new("log-level")
+                .about("Changes the log level until the config is reloaded.")
+                .arg(
+                    Arg::new("level")
+                    .value_name("LEVEL")
+                    .required(true)
+                    .ignore_case(true)
+                    .value_parser(["Error", "Warn", "Info", "Debug", "Trace"])
+                    .help("New log level.")
+                )
+            )
+        )
 }
 
 fn run(cli: clap::ArgMatches) -> Result<(), Error> {
@@ -130,6 +555,7 @@ fn run(cli: clap::ArgMatches) -> Result<(), Error> {
         Some(("devices", sub_m)) => devices::run(sub_m),
         Some(("run", sub_m)) => run::run(sub_m),
         Some(("config", sub_m)) => config::run(sub_m),
+        Some(("ctl", sub_m)) => control::run(sub_m),
         _ => Ok(()),
     };
 }
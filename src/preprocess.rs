@@ -0,0 +1,587 @@
+use super::document::{format_path, Segment};
+use super::types::ConfigFormat;
+use super::util;
+use anyhow::Error;
+use serde_json::{Map, Value};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Steps the config file goes through between being read and being deserialized.
+// Included files are read and their device entries appended to the main file's, then the
+// controls that use one of the named `commands` get it expanded in place, and finally the
+// `${...}` references in device names and commands are filled in from the environment.
+
+// A file that is part of the config, the main one or an included one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub path: PathBuf,
+    pub format: ConfigFormat,
+    pub value: Value,
+}
+
+// A device or named command that is defined in more than one place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    // `Device` or `Command`
+    pub kind: &'static str,
+    pub name: String,
+    // Index in the sources of the repeated definition, and where it is in that file
+    pub source: usize,
+    pub path: Vec<Segment>,
+    // File where it was defined first
+    pub first: PathBuf,
+}
+
+// Reads the config and everything it includes, and merges it into a single value
+pub fn load(path: &Path, format: ConfigFormat) -> Result<Value, Error> {
+    let mut merged = merge(&collect(path, format)?)?;
+    expand(&mut merged)?;
+    interpolate(&mut merged)?;
+    Ok(merged)
+}
+
+// Same as `load`, for a main file that is already in memory.
+// References aren't filled in, the file has to be valid even where they aren't set.
+pub fn resolve(path: &Path, format: ConfigFormat, value: Value) -> Result<Value, Error> {
+    let mut sources = Vec::new();
+    collect_into(
+        Source {
+            path: path.to_path_buf(),
+            format,
+            value,
+        },
+        &mut Vec::new(),
+        &mut sources,
+    )?;
+    let mut merged = merge(&sources)?;
+    expand(&mut merged)?;
+    Ok(merged)
+}
+
+// Reads the config and every file it includes, the main file first
+pub fn collect(path: &Path, format: ConfigFormat) -> Result<Vec<Source>, Error> {
+    let mut sources = Vec::new();
+    collect_into(read(path, format)?, &mut Vec::new(), &mut sources)?;
+    Ok(sources)
+}
+
+fn read(path: &Path, format: ConfigFormat) -> Result<Source, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| Error::msg(format!("Cannot read {:?}: {}", path, error)))?;
+    let value = util::parse_value(&contents, format)
+        .map_err(|error| Error::msg(format!("Cannot parse {:?}: {}", path, error)))?;
+    Ok(Source {
+        path: path.to_path_buf(),
+        format,
+        value,
+    })
+}
+
+fn collect_into(
+    source: Source,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<(), Error> {
+    // A main file that isn't written yet can't be included by anything
+    let canonical = source
+        .path
+        .canonicalize()
+        .unwrap_or_else(|_| source.path.clone());
+    let includes = include_paths(&source.path, &source.value)?;
+    sources.push(source);
+
+    stack.push(canonical);
+    for include in includes {
+        let canonical = include.canonicalize()?;
+        if stack.contains(&canonical) {
+            return Err(Error::msg(format!("{:?} includes itself", include)));
+        }
+        // Files included from several places are only read once
+        if sources
+            .iter()
+            .any(|source| source.path.canonicalize().ok().as_ref() == Some(&canonical))
+        {
+            continue;
+        }
+        let format = ConfigFormat::from_path(&include).unwrap_or(ConfigFormat::Json);
+        collect_into(read(&include, format)?, stack, sources)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+// Files matched by the `include` key, relative to the file that includes them
+pub fn include_paths(path: &Path, value: &Value) -> Result<Vec<PathBuf>, Error> {
+    let patterns = match value.get("include") {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(patterns)) => patterns,
+        Some(_) => {
+            return Err(Error::msg(format!(
+                "`include` in {:?} must be an array of paths",
+                path
+            )))
+        }
+    };
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.as_str().ok_or(Error::msg(format!(
+            "`include` in {:?} must be an array of paths",
+            path
+        )))?;
+        let full_pattern = base.join(pattern);
+
+        // Plain paths must exist, globs may match nothing
+        if !pattern.contains(['*', '?', '[']) {
+            if !full_pattern.is_file() {
+                return Err(Error::msg(format!(
+                    "Included file {:?} not found (included from {:?})",
+                    full_pattern, path
+                )));
+            }
+            paths.push(full_pattern);
+            continue;
+        }
+
+        let pattern_text = full_pattern.to_str().ok_or(Error::msg(format!(
+            "Include pattern {} is not valid UTF-8",
+            pattern
+        )))?;
+        let mut matched = glob::glob(pattern_text)
+            .map_err(|error| Error::msg(format!("Invalid include pattern {}: {}", pattern, error)))?
+            .collect::<Result<Vec<PathBuf>, _>>()?;
+        matched.retain(|path| path.is_file());
+        matched.sort();
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
+
+// Devices configured in more than one place, matched the same way ports are,
+// and named commands defined more than once
+pub fn duplicates(sources: &[Source]) -> Vec<Duplicate> {
+    let mut devices: Vec<(String, PathBuf)> = Vec::new();
+    let mut commands: Vec<(String, PathBuf)> = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for (source_index, source) in sources.iter().enumerate() {
+        let entries = source.value["config"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for (index, entry) in entries.iter().enumerate() {
+            let device = match entry["device"].as_str() {
+                Some(device) => device,
+                None => continue,
+            };
+            match devices
+                .iter()
+                .find(|(other, _)| util::matches_device(other, device))
+            {
+                Some((_, first)) => duplicates.push(Duplicate {
+                    kind: "Device",
+                    name: device.to_string(),
+                    source: source_index,
+                    path: vec![
+                        Segment::Key(String::from("config")),
+                        Segment::Index(index),
+                        Segment::Key(String::from("device")),
+                    ],
+                    first: first.clone(),
+                }),
+                None => devices.push((device.to_string(), source.path.clone())),
+            }
+        }
+
+        for name in source.value["commands"]
+            .as_object()
+            .into_iter()
+            .flat_map(|commands| commands.keys())
+        {
+            match commands.iter().find(|(other, _)| other == name) {
+                Some((_, first)) => duplicates.push(Duplicate {
+                    kind: "Command",
+                    name: name.clone(),
+                    source: source_index,
+                    path: vec![
+                        Segment::Key(String::from("commands")),
+                        Segment::Key(name.clone()),
+                    ],
+                    first: first.clone(),
+                }),
+                None => commands.push((name.clone(), source.path.clone())),
+            }
+        }
+    }
+    duplicates
+}
+
+// Appends the device entries and named commands of every included file to the main file's
+pub fn merge(sources: &[Source]) -> Result<Value, Error> {
+    if let Some(duplicate) = duplicates(sources).first() {
+        return Err(Error::msg(format!(
+            "{} {} is defined both in {:?} and in {:?}",
+            duplicate.kind, duplicate.name, duplicate.first, sources[duplicate.source].path
+        )));
+    }
+
+    let (main, included) = sources
+        .split_first()
+        .ok_or(Error::msg("There is no config to merge"))?;
+    let mut merged = main.value.clone();
+    let root = merged
+        .as_object_mut()
+        .ok_or(Error::msg(format!("{:?} is not an object", main.path)))?;
+    root.remove("include");
+
+    let mut entries = root
+        .get("config")
+        .and_then(|config| config.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut commands = root
+        .get("commands")
+        .and_then(|commands| commands.as_object())
+        .cloned()
+        .unwrap_or_default();
+    for source in included {
+        match source.value.get("config") {
+            Some(Value::Array(config)) => entries.extend(config.iter().cloned()),
+            Some(_) => {
+                return Err(Error::msg(format!(
+                    "`config` in {:?} must be an array of devices",
+                    source.path
+                )))
+            }
+            None => {}
+        }
+        match source.value.get("commands") {
+            Some(Value::Object(named)) => commands.extend(named.clone()),
+            Some(_) => {
+                return Err(Error::msg(format!(
+                    "`commands` in {:?} must be an object of named commands",
+                    source.path
+                )))
+            }
+            None => {}
+        }
+    }
+    root.insert(String::from("config"), Value::Array(entries));
+    if !commands.is_empty() {
+        root.insert(String::from("commands"), Value::Object(commands));
+    }
+
+    Ok(merged)
+}
+
+// Replaces every reference to a named command with the command itself
+pub fn expand(value: &mut Value) -> Result<(), Error> {
+    let commands = value["commands"].as_object().cloned().unwrap_or_default();
+    let entries = match value
+        .get_mut("config")
+        .and_then(|config| config.as_array_mut())
+    {
+        Some(entries) => entries,
+        None => return Ok(()),
+    };
+
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let entry_path = vec![Segment::Key(String::from("config")), Segment::Index(index)];
+        for (key, field) in entry.as_object_mut().into_iter().flatten() {
+            for (path, data) in entry_commands(key, field) {
+                if data.get("use").is_some() {
+                    *data = expand_command(&commands, data).map_err(|error| {
+                        Error::msg(format!(
+                            "{} (at {})",
+                            error,
+                            format_path(&[entry_path.clone(), path].concat())
+                        ))
+                    })?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Every place in a field of a device entry where a command goes, with its path in the entry
+fn entry_commands<'v>(key: &str, field: &'v mut Value) -> Vec<(Vec<Segment>, &'v mut Value)> {
+    let path = vec![Segment::Key(key.to_string())];
+    match key {
+        "on_shutdown" => vec![(path, field)],
+        "controls" => control_commands(path, field),
+        "banks" => {
+            let mut commands = Vec::new();
+            for (bank, controls) in field.as_object_mut().into_iter().flatten() {
+                let bank_path = [path.clone(), vec![Segment::Key(bank.clone())]].concat();
+                commands.extend(control_commands(bank_path, controls));
+            }
+            commands
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Every command of a list of controls at `path`
+fn control_commands(path: Vec<Segment>, controls: &mut Value) -> Vec<(Vec<Segment>, &mut Value)> {
+    let mut commands = Vec::new();
+    for (name, control) in controls.as_object_mut().into_iter().flatten() {
+        let command = match control
+            .get_mut("command")
+            .and_then(|command| command.as_object_mut())
+        {
+            Some(command) => command,
+            None => continue,
+        };
+        for (data_key, data) in command.iter_mut() {
+            let data_path = vec![
+                Segment::Key(name.clone()),
+                Segment::Key(String::from("command")),
+                Segment::Key(data_key.clone()),
+            ];
+            if ["execute", "on", "off"].contains(&data_key.as_str()) {
+                commands.push(([path.clone(), data_path].concat(), data));
+            } else if ["states", "zones"].contains(&data_key.as_str()) {
+                // The states of a cycle and the zones of a trigger have a command each
+                for (index, state) in data.as_array_mut().into_iter().flatten().enumerate() {
+                    if let Some(execute) = state.get_mut("execute") {
+                        let state_path =
+                            vec![Segment::Index(index), Segment::Key(String::from("execute"))];
+                        commands.push((
+                            [path.clone(), data_path.clone(), state_path].concat(),
+                            execute,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    commands
+}
+
+// Fills in the named command a reference points to with the parameters it gives
+pub fn expand_command(commands: &Map<String, Value>, reference: &Value) -> Result<Value, Error> {
+    let name = reference["use"]
+        .as_str()
+        .ok_or(Error::msg("`use` must be the name of a command"))?;
+    let command = commands
+        .get(name)
+        .ok_or(Error::msg(format!("There is no command named `{}`", name)))?;
+    let with = match reference.get("with") {
+        None => Map::new(),
+        Some(Value::Object(with)) => with.clone(),
+        Some(_) => return Err(Error::msg("`with` must be an object of parameter values")),
+    };
+
+    let parameters = parameters(command);
+    for (parameter, value) in &with {
+        if !parameters.contains(parameter) {
+            return Err(Error::msg(format!(
+                "Command `{}` has no parameter `{}`",
+                name, parameter
+            )));
+        }
+        if !value.is_string() {
+            return Err(Error::msg(format!(
+                "The value of parameter `{}` must be a string",
+                parameter
+            )));
+        }
+    }
+    if let Some(missing) = parameters
+        .iter()
+        .find(|parameter| !with.contains_key(*parameter))
+    {
+        return Err(Error::msg(format!(
+            "Command `{}` needs a value for parameter `{}`",
+            name, missing
+        )));
+    }
+
+    let mut expanded = command.clone();
+    fill(&mut expanded, &with);
+    Ok(expanded)
+}
+
+// Names of every `{{parameter}}` in the strings of a named command, in order
+pub fn parameters(command: &Value) -> Vec<String> {
+    let mut parameters = Vec::new();
+    let mut strings = vec![command];
+    while let Some(value) = strings.pop() {
+        match value {
+            Value::String(text) => {
+                let mut rest = text.as_str();
+                while let Some(start) = rest.find("{{") {
+                    let end = match rest[start..].find("}}") {
+                        Some(end) => start + end,
+                        None => break,
+                    };
+                    let parameter = rest[start + 2..end].to_string();
+                    if !parameters.contains(&parameter) {
+                        parameters.push(parameter);
+                    }
+                    rest = &rest[end + 2..];
+                }
+            }
+            Value::Array(values) => strings.extend(values.iter().rev()),
+            Value::Object(values) => strings.extend(values.values().rev()),
+            _ => {}
+        }
+    }
+    parameters
+}
+
+fn fill(value: &mut Value, with: &Map<String, Value>) {
+    match value {
+        Value::String(text) => {
+            for (parameter, replacement) in with {
+                let replacement = replacement.as_str().unwrap_or_default();
+                *text = text.replace(&format!("{{{{{}}}}}", parameter), replacement);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| fill(value, with)),
+        Value::Object(values) => values.values_mut().for_each(|value| fill(value, with)),
+        _ => {}
+    }
+}
+
+// Fills in the `${...}` references of every value that is read when running
+pub fn interpolate(value: &mut Value) -> Result<(), Error> {
+    interpolate_with(value, |name| env::var_os(name))
+}
+
+pub fn interpolate_with<F: Fn(&str) -> Option<OsString>>(
+    value: &mut Value,
+    var: F,
+) -> Result<(), Error> {
+    let entries = match value
+        .get_mut("config")
+        .and_then(|config| config.as_array_mut())
+    {
+        Some(entries) => entries,
+        None => return Ok(()),
+    };
+
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let entry_path = vec![Segment::Key(String::from("config")), Segment::Index(index)];
+        let mut strings: Vec<(Vec<Segment>, &mut Value)> = Vec::new();
+        let entry = match entry.as_object_mut() {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        for (key, field) in entry.iter_mut() {
+            let path = [entry_path.clone(), vec![Segment::Key(key.clone())]].concat();
+            match key.as_str() {
+                "device" | "feedback_output" => strings.push((path, field)),
+                "passthrough" => {
+                    if let Some(output) = field.get_mut("output") {
+                        strings.push((
+                            [path, vec![Segment::Key(String::from("output"))]].concat(),
+                            output,
+                        ));
+                    }
+                }
+                _ => {
+                    for (data_path, data) in entry_commands(key, field) {
+                        let data_path = [entry_path.clone(), data_path].concat();
+                        for (field, value) in data.as_object_mut().into_iter().flatten() {
+                            let field_path =
+                                [data_path.clone(), vec![Segment::Key(field.clone())]].concat();
+                            match (field.as_str(), value) {
+                                ("cmd", value) => strings.push((field_path, value)),
+                                ("args", Value::Array(args)) => {
+                                    for (arg_index, arg) in args.iter_mut().enumerate() {
+                                        strings.push((
+                                            [field_path.clone(), vec![Segment::Index(arg_index)]]
+                                                .concat(),
+                                            arg,
+                                        ));
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (path, value) in strings {
+            if let Value::String(text) = value {
+                *text = interpolate_text(text, &var).map_err(|error| {
+                    Error::msg(format!("{} (at {})", error, format_path(&path)))
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Fills in `${NAME}`, `${env:NAME}` and `${file:PATH}` with the environment variable or the
+// contents of the file. `${NAME:-default}` is used when it is unset or empty, `$${` is a plain `${`.
+pub fn interpolate_text<F: Fn(&str) -> Option<OsString>>(
+    text: &str,
+    var: &F,
+) -> Result<String, Error> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or(Error::msg(format!(
+            "`${{` without a closing `}}` in `{}`",
+            text
+        )))? + start;
+        let reference = &rest[start + 2..end];
+        let (reference, default) = match reference.split_once(":-") {
+            Some((reference, default)) => (reference, Some(default)),
+            None => (reference, None),
+        };
+
+        let (kind, name) = reference.split_once(':').unwrap_or(("env", reference));
+
+        let value = match (kind, name) {
+            ("file", path) if !path.is_empty() => match fs::read_to_string(path) {
+                Ok(contents) => Some(contents.trim_end_matches(['\n', '\r']).to_string()),
+                Err(_) if default.is_some() => None,
+                Err(error) => {
+                    return Err(Error::msg(format!(
+                        "Cannot read file {} referenced in `{}`: {}",
+                        path, text, error
+                    )))
+                }
+            },
+            ("env", name) if !name.is_empty() => match var(name).filter(|value| !value.is_empty()) {
+                Some(value) => Some(value.into_string().map_err(|_| {
+                    Error::msg(format!("Environment variable {} is not valid UTF-8", name))
+                })?),
+                None if default.is_some() => None,
+                None => {
+                    return Err(Error::msg(format!(
+                        "Environment variable {} referenced in `{}` is not set",
+                        name, text
+                    )))
+                }
+            },
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unknown reference `${{{}}}` in `{}`, expected `${{NAME}}`, `${{env:NAME}}` or `${{file:PATH}}`",
+                    reference, text
+                )))
+            }
+        };
+        result.push_str(&value.unwrap_or_else(|| default.unwrap_or_default().to_string()));
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
@@ -0,0 +1,239 @@
+use super::document::{self, ConfigDocument};
+use super::types::{self, CommandKind};
+use super::util;
+use anyhow::Error;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::str::FromStr;
+
+// Interactive questions shared by the config subcommands.
+// Every answer is checked against the config types before moving on, so mistakes are caught
+// while they can still be fixed instead of when the whole file is validated.
+
+// Checks a piece of config against the type it will be read as
+pub fn check<T: DeserializeOwned>(value: &Value) -> Result<(), Error> {
+    serde_json::from_value::<T>(value.clone())
+        .map(|_| ())
+        .map_err(Error::from)
+}
+
+pub fn ask_text(question: &str, default: Option<&str>) -> Result<String, Error> {
+    loop {
+        let answer = util::prompt(question, default)?;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+    }
+}
+
+pub fn ask_yes_no(question: &str, default: bool) -> Result<bool, Error> {
+    loop {
+        let answer = util::prompt(question, Some(if default { "y" } else { "n" }))?;
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Answer yes or no."),
+        }
+    }
+}
+
+pub fn ask_number<T: FromStr + Display>(question: &str, default: Option<T>) -> Result<T, Error> {
+    let default = default.map(|default| default.to_string());
+    loop {
+        match util::prompt(question, default.as_deref())?.parse() {
+            Ok(number) => return Ok(number),
+            Err(_) => println!("That is not a valid number here."),
+        }
+    }
+}
+
+pub fn ask_kind(default: Option<&CommandKind>) -> Result<CommandKind, Error> {
+    let default = default.map(|kind| format!("{:?}", kind));
+    loop {
+        let answer = util::prompt(
+            "Kind of control (Encoder, Switch, Trigger, Cycle):",
+            default.as_deref(),
+        )?;
+        match answer.to_lowercase().as_str() {
+            "encoder" => return Ok(CommandKind::Encoder),
+            "switch" => return Ok(CommandKind::Switch),
+            "trigger" => return Ok(CommandKind::Trigger),
+            "cycle" => return Ok(CommandKind::Cycle),
+            _ => println!("Unknown kind {}", answer),
+        }
+    }
+}
+
+pub fn ask_control_name(
+    document: &ConfigDocument,
+    device: &str,
+    default: Option<&str>,
+) -> Result<String, Error> {
+    loop {
+        let name = ask_text("Name of the control:", default)?;
+        if !document::is_valid_control_name(&name) {
+            println!(
+                "Names must be lowercase letters, digits or low dashes, starting with a letter."
+            );
+            continue;
+        }
+        if document.has_control(device, &name)? {
+            println!("There is already a control named {}.", name);
+            continue;
+        }
+        return Ok(name);
+    }
+}
+
+pub fn ask_command(kind: &CommandKind) -> Result<Value, Error> {
+    let command = match kind {
+        CommandKind::Encoder => {
+            let source = loop {
+                let answer = util::prompt(
+                    "Read the value from (key, PolyAftertouch, ChannelPressure):",
+                    Some("key"),
+                )?;
+                if answer == "key" {
+                    break None;
+                }
+                match check::<types::PressureSource>(&json!(answer)) {
+                    Ok(()) => break Some(answer),
+                    Err(_) => println!("It must be key, PolyAftertouch or ChannelPressure."),
+                }
+            };
+            println!("Use a placeholder like @VALUE@ where the mapped value of the control goes.");
+            let mut encoder = json!({
+                "kind": "Encoder",
+                "execute": ask_command_data("Command to run:", true)?
+            });
+            if let Some(source) = source {
+                encoder["source"] = json!(source);
+            }
+            encoder
+        }
+        CommandKind::Switch => {
+            let momentary = ask_yes_no("Is it ON only while held?", false)?;
+            let on = ask_command_data("Command to run when turned ON:", false)?;
+            let off = ask_command_data("Command to run when turned OFF:", false)?;
+            let initial_state = loop {
+                if momentary {
+                    break json!("OFF");
+                }
+                let answer = json!(util::prompt(
+                    "Initial state (ON, OFF, restore):",
+                    Some("OFF")
+                )?);
+                match check::<types::InitialSwitchState>(&answer) {
+                    Ok(()) => break answer,
+                    Err(_) => println!("The initial state must be ON, OFF or restore."),
+                }
+            };
+            let mut switch = json!({
+                "kind": "Switch",
+                "on": on,
+                "off": off,
+                "initial_state": initial_state
+            });
+            if momentary {
+                switch["momentary"] = json!(true);
+            }
+            let group = util::prompt(
+                "Group of switches only one of which is ON (optional):",
+                None,
+            )?;
+            if !group.is_empty() {
+                switch["group"] = json!(group);
+            }
+            switch
+        }
+        CommandKind::Trigger => {
+            if !ask_yes_no(
+                "Run different commands depending on how hard it's hit?",
+                false,
+            )? {
+                return Ok(json!({
+                    "kind": "Trigger",
+                    "execute": ask_command_data("Command to run:", false)?
+                }));
+            }
+            println!("Give the velocity zones from soft to hard, up to 127.");
+            let mut zones: Vec<Value> = Vec::new();
+            let mut min: u16 = 1;
+            while min <= 127 {
+                let max = ask_number(
+                    &format!("Hardest velocity of the zone from {}:", min),
+                    Some(127),
+                )?;
+                if max < min || max > 127 {
+                    println!("It must be between {} and 127.", min);
+                    continue;
+                }
+                let execute = ask_command_data("Command to run for it:", false)?;
+                zones.push(json!({ "values": { "min": min, "max": max }, "execute": execute }));
+                min = max + 1;
+            }
+            json!({
+                "kind": "Trigger",
+                "zones": zones
+            })
+        }
+        CommandKind::Cycle => {
+            println!("Name the states in order, leave the name empty when done.");
+            let mut states: Vec<Value> = Vec::new();
+            loop {
+                let name = util::prompt("Name of the state:", None)?;
+                if name.is_empty() && !states.is_empty() {
+                    break;
+                }
+                if name.is_empty() || states.iter().any(|state| state["name"] == name) {
+                    println!("States need a name, different from the others.");
+                    continue;
+                }
+                let execute = ask_command_data("Command to run when reached:", false)?;
+                states.push(json!({ "name": name, "execute": execute }));
+            }
+            json!({
+                "kind": "Cycle",
+                "states": states
+            })
+        }
+    };
+    check::<types::Command>(&command)?;
+    Ok(command)
+}
+
+// Commands are split on whitespace, shell quoting is not interpreted
+pub fn ask_command_data(question: &str, mapped: bool) -> Result<Value, Error> {
+    loop {
+        let line = ask_text(question, None)?;
+        let mut words = line.split_whitespace().map(String::from);
+        let cmd = words.next().unwrap_or_default();
+        let args: Vec<String> = words.collect();
+
+        let mut data = json!({ "cmd": cmd, "args": args });
+
+        if mapped {
+            let placeholder = ask_text("Placeholder to replace with the value:", Some("@VALUE@"))?;
+            if line.contains(&placeholder) {
+                data["replace"] = json!(placeholder);
+                data["map_min"] = json!(ask_number::<i32>(
+                    "Value at the minimum position:",
+                    Some(0)
+                )?);
+                data["map_max"] = json!(ask_number::<i32>(
+                    "Value at the maximum position:",
+                    Some(127)
+                )?);
+            }
+        }
+
+        match check::<types::CommandData>(&data) {
+            Ok(()) if data["map_min"].as_i64() > data["map_max"].as_i64() => {
+                println!("The minimum value must not be more than the maximum.")
+            }
+            Ok(()) => return Ok(data),
+            Err(error) => println!("Invalid command: {}", error),
+        }
+    }
+}
@@ -1,99 +1,908 @@
+use super::control;
+use super::preprocess;
+use super::state::{self, StateFile};
 use super::types::{
-    Activation, ActivationKind, Command, CommandData, CommandKind, Config, ControlList,
-    ControlListByKey, InitialSwitchState, KeyEvent, KeyState, Threshold,
+    Activation, ActivationKind, Command, CommandData, CommandKind, Config, ConfigFile,
+    ConfigFormat, ControlList, ControlListByKey, ControlRequest, ControlResponse,
+    InitialSwitchState, KeyEvent, KeyState, MessageType, MidiMessage, Passthrough, SavedDevice,
+    Switch, SwitchState, Threshold,
 };
 use super::util::{self, Logger};
 use anyhow::Error;
-use midir::{Ignore, MidiInput, MidiInputConnection};
+use midir::os::unix::{VirtualInput, VirtualOutput};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use serde_json::{json, Map, Value};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use std::collections::HashMap;
-use std::process;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Stdio};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+
+// How often the config files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// How often signals are checked for
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+// Exit code when commands had to be stopped or failed while shutting down
+const EXIT_UNCLEAN_SHUTDOWN: i32 = 1;
+
+// States of the controls of a device by key, shared with its connection callback
+type States = Arc<Mutex<HashMap<u8, Option<KeyState>>>>;
+// Pressure controls of a device by name, kept by its worker
+pub type Pressures = HashMap<String, Pressure>;
+
+// When a pressure control last ran and with what value, and the latest value held back since
+// with when it's due
+#[derive(Debug, Default)]
+pub struct Pressure {
+    sent: Option<(Instant, u8)>,
+    held: Option<(Instant, u8)>,
+}
+
+// What the connection callbacks share with the main thread
+#[derive(Clone, Default)]
+pub struct Runtime {
+    // Set when shutting down, so no new events are handled
+    stopping: Arc<AtomicBool>,
+    // Commands running right now, by process id
+    children: Arc<Mutex<HashMap<u32, Child>>>,
+    // Where switch states and encoder values are kept between runs, if anywhere
+    state: Option<Arc<StateFile>>,
+}
+
+impl Runtime {
+    // Keeps the new state of a control in the state file, which is written in the background
+    fn remember(&self, device: &str, kind: &CommandKind, state: &KeyState) {
+        let state_file = match &self.state {
+            Some(state_file) => state_file,
+            None => return,
+        };
+        match (kind, state.detections.last()) {
+            (CommandKind::Switch, _) => state_file.save_switch(
+                device,
+                &state.control,
+                switch_state(InitialSwitchState::OFF, Some(state)),
+            ),
+            (CommandKind::Encoder, Some(value)) => {
+                state_file.save_encoder(device, &state.control, *value)
+            }
+            _ => {}
+        }
+    }
+
+    // States to connect a device with, restored from the state file when there is one
+    fn starting_states(&self, config: &Config) -> HashMap<u8, Option<KeyState>> {
+        match &self.state {
+            Some(state_file) => restored_states(config, &state_file.device(&config.device)),
+            None => initial_states(config),
+        }
+    }
+}
+
+// The running service, as seen from the main thread
+struct Service {
+    path: PathBuf,
+    format: ConfigFormat,
+    // Every file the config is read from, to notice changes
+    watched: Vec<(PathBuf, Option<SystemTime>)>,
+    // Names of the devices in the config, connected or not
+    configured: Vec<String>,
+    devices: Vec<Device>,
+    runtime: Runtime,
+    log: Logger,
+}
+
+// A device being listened to
+struct Device {
+    // As it's listened to, with the controls of the active bank
+    config: Config,
+    // As it is in the config file
+    source: Config,
+    bank: Option<String>,
+    states: States,
+    connection: MidiInputConnection<()>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceChange {
+    Added(Config),
+    Removed(String),
+    Changed(Config),
+}
 
 pub fn run(cli: &clap::ArgMatches) -> Result<(), Error> {
-    let path = cli.get_one::<String>("path");
+    let (path, format) = util::config_location(cli)?;
+    let shutdown_timeout = Duration::from_secs(
+        cli.get_one::<u64>("shutdown-timeout")
+            .copied()
+            .unwrap_or_default(),
+    );
+    let mut runtime = Runtime::default();
 
-    let config_data = util::read_user_config(path)?;
+    let config_data = read_config(&path, format)?;
 
     let log_level = config_data.log_level.clone();
 
-    let log = Logger::new(log_level);
+    // Every copy of this logger follows the log level of the latest config, or of `ctl log-level`
+    let log = Logger::runtime(log_level);
 
+    // Only where it came from, the config has its `${...}` references filled in, secrets included
     log.trace(
         "configuration file loaded correctly, log level set.",
-        Some(&config_data),
+        path.display(),
     );
 
-    for config in config_data.config {
-        let builder = thread::Builder::new();
+    // States are only kept when asked for, or when a switch wants to be restored
+    let state_path = match cli.get_one::<String>("state-file") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if config_data.config.iter().any(restores) => state::default_path(),
+        None => None,
+    };
+    if let Some(state_path) = state_path {
+        let state_file = StateFile::open(state_path, log);
+        log.info(format!("Keeping states in {}", state_file.path().display()).as_str());
+        runtime.state = Some(state_file);
+    }
 
-        log.trace("Built new thread", &builder);
+    let mut devices = Vec::new();
+    for config in config_data.config.iter().cloned() {
+        log.trace("Passing current config to device handler", &config.device);
+        let bank = config.initial_bank.clone();
+        let states = runtime.starting_states(&config.with_bank(bank.as_deref()));
+        match connect(config.clone(), bank, states, &runtime, log) {
+            Ok(device) => devices.push(device),
+            Err(error) => {
+                log.error(error.to_string().as_str());
+                log.warn(format!("Device {} will not be listened to.", config.device).as_str());
+            }
+        }
+    }
+    if devices.is_empty() && !config_data.config.is_empty() {
+        return Err(Error::msg(
+            "None of the configured devices could be connected.",
+        ));
+    }
 
-        log.trace("Passing current config to device handler", &config);
+    // Requests from `ctl` are answered on this thread. Without a socket nothing ever arrives,
+    // and waiting for requests just paces the loop.
+    let socket = control::socket_path(cli);
+    let (sender, requests) = mpsc::channel();
+    let listening = match control::listen(&socket, sender.clone(), log) {
+        Ok(()) => true,
+        Err(error) => {
+            log.warn(format!("The control socket is not available: {}", error).as_str());
+            false
+        }
+    };
 
-        let handle =
-            builder.spawn(
-                move || match handle_device(config.device.clone(), config, log) {
-                    Ok(_) => (),
-                    Err(error) => {
-                        log.error(error.to_string().as_str());
-                        log.warn("Probably exiting now.");
+    // The connections run their callbacks on their own threads, this one just watches the config
+    // and waits for signals. A second SIGINT or SIGTERM while shutting down exits right away.
+    let reload = Arc::new(AtomicBool::new(false));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(
+            signal,
+            EXIT_UNCLEAN_SHUTDOWN,
+            Arc::clone(&shutdown),
+        )?;
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+    let mut service = Service {
+        watched: watched_files(&path, format),
+        configured: config_data
+            .config
+            .iter()
+            .map(|config| config.device.clone())
+            .collect(),
+        path,
+        format,
+        devices,
+        runtime: runtime.clone(),
+        log,
+    };
+    let mut last_check = Instant::now();
+    log.info(
+        format!(
+            "Watching {} for changes, send SIGHUP to reload it.",
+            service.path.display()
+        )
+        .as_str(),
+    );
+
+    while !shutdown.load(Ordering::Relaxed) {
+        if let Ok((request, reply)) = requests.recv_timeout(SIGNAL_INTERVAL) {
+            service.handle(request, reply);
+        }
+        let reloading = reload.swap(false, Ordering::Relaxed);
+        if !reloading && last_check.elapsed() < WATCH_INTERVAL {
+            continue;
+        }
+        last_check = Instant::now();
+        if reloading || watched_files(&service.path, service.format) != service.watched {
+            if let Err(error) = service.reload() {
+                log.error(error.to_string().as_str());
+            }
+        }
+    }
+    drop(sender);
+
+    if listening {
+        let _ = fs::remove_file(&socket);
+    }
+    if !shut_down(service.devices, &runtime, shutdown_timeout, log) {
+        log.warn("Shutdown was not clean.");
+        process::exit(EXIT_UNCLEAN_SHUTDOWN);
+    }
+    log.info("Stopped.");
+    Ok(())
+}
+
+// Stops handling events, waits for running commands, closes every device and runs their
+// `on_shutdown` commands. Returns whether every command finished on its own and succeeded.
+fn shut_down(devices: Vec<Device>, runtime: &Runtime, timeout: Duration, log: Logger) -> bool {
+    log.info("Shutting down...");
+    runtime.stopping.store(true, Ordering::Relaxed);
+    let mut clean = wait_for_children(runtime, timeout, log);
+    if let Some(state_file) = &runtime.state {
+        if let Err(error) = state_file.flush() {
+            log.warn(format!("Could not write the state file: {}", error).as_str());
+        }
+    }
+
+    let mut on_shutdown = Vec::new();
+    for device in devices {
+        device.connection.close();
+        log.info(format!("Closed {}", device.config.device).as_str());
+        if let Some(command) = device.config.on_shutdown {
+            let runtime = runtime.clone();
+            let control = format!("on_shutdown of {}", device.config.device);
+            on_shutdown.push(thread::spawn(move || {
+                spawn_command(&control, &command, &None, &runtime, log)
+            }));
+        }
+    }
+
+    if !on_shutdown.is_empty() {
+        let deadline = Instant::now() + timeout;
+        while on_shutdown.iter().any(|handle| !handle.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        clean &= wait_for_children(runtime, Duration::ZERO, log);
+        for handle in on_shutdown {
+            match handle.join() {
+                Ok(Ok(command)) => log.info(format!("Executed command {}", command).as_str()),
+                Ok(Err(error)) => {
+                    clean = false;
+                    log.error(&error.to_string())
+                }
+                Err(_) => clean = false,
+            }
+        }
+    }
+    clean
+}
+
+// Waits for the running commands to finish, and stops the ones still running after the timeout.
+// Returns whether all of them finished on their own.
+pub fn wait_for_children(runtime: &Runtime, timeout: Duration, log: Logger) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !lock(&runtime.children).is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut children = lock(&runtime.children);
+    for (id, child) in children.iter_mut() {
+        log.warn(format!("Command with pid {} is still running, stopping it", id).as_str());
+        if let Err(error) = child.kill() {
+            log.error(format!("Could not stop pid {}: {}", id, error).as_str());
+        }
+    }
+    children.is_empty()
+}
+
+fn read_config(path: &Path, format: ConfigFormat) -> Result<ConfigFile, Error> {
+    Ok(util::read_user_config(
+        Some(&path.display().to_string()),
+        Some(&format),
+    )?)
+}
+
+// Every file the config is read from, with when it was last modified
+fn watched_files(path: &Path, format: ConfigFormat) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let paths = match preprocess::collect(path, format) {
+        Ok(sources) => sources.into_iter().map(|source| source.path).collect(),
+        Err(_) => vec![path.to_path_buf()],
+    };
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+impl Service {
+    // Applies a changed config, reconnecting only the devices that changed.
+    // Returns how many devices were added, removed or changed.
+    fn reload(&mut self) -> Result<usize, Error> {
+        let log = self.log;
+        self.watched = watched_files(&self.path, self.format);
+        log.info("Reloading the config...");
+        let config_data = read_config(&self.path, self.format).map_err(|error| {
+            Error::msg(format!(
+                "The new config is not valid, keeping the old one: {}",
+                error
+            ))
+        })?;
+        Logger::set_runtime_level(config_data.log_level);
+        self.configured = config_data
+            .config
+            .iter()
+            .map(|config| config.device.clone())
+            .collect();
+
+        let current: Vec<Config> = self
+            .devices
+            .iter()
+            .map(|device| device.source.clone())
+            .collect();
+        let changes = diff_configs(&current, &config_data.config);
+        if changes.is_empty() {
+            log.info("No device changed.");
+        }
+
+        let changed = changes.len();
+        for change in changes {
+            match change {
+                DeviceChange::Removed(name) => {
+                    if let Some(index) = self
+                        .devices
+                        .iter()
+                        .position(|device| device.config.device == name)
+                    {
+                        self.devices.remove(index).connection.close();
+                        log.info(format!("Stopped listening to {}", name).as_str());
                     }
-                },
-            )?;
+                }
+                DeviceChange::Added(config) => {
+                    let bank = config.initial_bank.clone();
+                    let states = self
+                        .runtime
+                        .starting_states(&config.with_bank(bank.as_deref()));
+                    match connect(config.clone(), bank, states, &self.runtime, log) {
+                        Ok(device) => self.devices.push(device),
+                        Err(error) => log.error(
+                            format!("Could not connect to {}: {}", config.device, error).as_str(),
+                        ),
+                    }
+                }
+                DeviceChange::Changed(config) => {
+                    let index = match self
+                        .devices
+                        .iter()
+                        .position(|device| device.config.device == config.device)
+                    {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    // The active bank stays active, if it's still there
+                    let bank = self.devices[index]
+                        .bank
+                        .clone()
+                        .filter(|bank| config.banks.contains_key(bank))
+                        .or(config.initial_bank.clone());
+                    match self.reconnect(index, config.clone(), bank) {
+                        Ok(()) => log.info(
+                            format!("Reconnected {} with its new config", config.device).as_str(),
+                        ),
+                        Err(error) => log.error(
+                            format!(
+                                "Could not reconnect {}, keeping its old config: {}",
+                                config.device, error
+                            )
+                            .as_str(),
+                        ),
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
 
-        log.trace("Thread started and handle set", &handle);
+    // Reconnects the device at `index` with another config or bank. Controls that didn't change
+    // keep their state, the rest start over. If it can't, the device is left as it was.
+    fn reconnect(
+        &mut self,
+        index: usize,
+        source: Config,
+        bank: Option<String>,
+    ) -> Result<(), Error> {
+        let log = self.log;
+        let config = source.with_bank(bank.as_deref());
+        let old = self.devices.remove(index);
+        let old_states = lock(&old.states).clone();
+        old.connection.close();
 
-        match handle.join() {
-            Ok(_) => {}
+        let mut states = self.runtime.starting_states(&config);
+        for (key, state) in carried_states(&old.config, &old_states, &config) {
+            if state.is_some() {
+                states.insert(key, state);
+            }
+        }
+        match connect(source, bank, states, &self.runtime, log) {
+            Ok(device) => {
+                self.devices.insert(index, device);
+                Ok(())
+            }
             Err(error) => {
-                log.default(format!("\n{:?}\n", error).as_str());
-                log.fatal("There has been a fatal error in a connection thread.");
+                match connect(old.source, old.bank, old_states, &self.runtime, log) {
+                    Ok(device) => self.devices.insert(index, device),
+                    Err(error) => log.error(error.to_string().as_str()),
+                }
+                Err(error)
+            }
+        }
+    }
+
+    // Makes another bank of a device the active one
+    fn switch_bank(&mut self, device: &str, bank: &str) -> Result<(), Error> {
+        let current = self.device(device)?;
+        if !current.source.banks.contains_key(bank) {
+            return Err(Error::msg(format!("{} has no bank {}.", device, bank)));
+        }
+        if current.bank.as_deref() == Some(bank) {
+            return Ok(());
+        }
+        let source = current.source.clone();
+        let index = self
+            .devices
+            .iter()
+            .position(|other| other.config.device == device)
+            .unwrap_or_default();
+        self.reconnect(index, source, Some(bank.to_string()))?;
+        self.log
+            .info(format!("Switched {} to bank {}", device, bank).as_str());
+        Ok(())
+    }
+
+    // Answers a request from the control socket. Fired commands answer from their own thread
+    // once they finish, so a slow command doesn't hold up the rest.
+    fn handle(&mut self, request: ControlRequest, reply: Sender<ControlResponse>) {
+        self.log
+            .debug(format!("Control request: {:?}", request).as_str());
+        let result = match request {
+            ControlRequest::Devices => Ok(Some(self.device_list())),
+            ControlRequest::States { device } => self.switch_states(device.as_deref()).map(Some),
+            ControlRequest::Set {
+                device,
+                control,
+                state,
+            } => self
+                .device(&device)
+                .and_then(|device| set_switch(device, &control, Some(state), &self.runtime))
+                .map(|_| None),
+            ControlRequest::Fire {
+                device,
+                control,
+                value,
+            } => return self.fire(&device, &control, value, reply),
+            ControlRequest::Bank { device, bank } => self.switch_bank(&device, &bank).map(|_| None),
+            ControlRequest::Reload => self
+                .reload()
+                .map(|changed| Some(json!({ "changed": changed }))),
+            ControlRequest::LogLevel { level } => {
+                Logger::set_runtime_level(level);
+                self.log
+                    .info(format!("Log level changed to {:?}", level).as_str());
+                Ok(None)
             }
         };
+        let _ = reply.send(match result {
+            Ok(result) => ControlResponse::success(result),
+            Err(error) => ControlResponse::failure(&error),
+        });
     }
-    Ok(())
+
+    fn device(&self, name: &str) -> Result<&Device, Error> {
+        match self
+            .devices
+            .iter()
+            .find(|device| device.config.device == name)
+        {
+            Some(device) => Ok(device),
+            None if self.configured.iter().any(|device| device == name) => {
+                Err(Error::msg(format!("Device {} is not connected.", name)))
+            }
+            None => Err(Error::msg(format!("Device {} is not in the config.", name))),
+        }
+    }
+
+    fn device_list(&self) -> Value {
+        Value::Array(
+            self.configured
+                .iter()
+                .map(|name| {
+                    let device = self
+                        .devices
+                        .iter()
+                        .find(|device| &device.config.device == name);
+                    json!({
+                        "device": name,
+                        "connected": device.is_some(),
+                        "bank": device.and_then(|device| device.bank.clone()),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    // Position of every switch and cycle, by device and control name
+    fn switch_states(&self, filter: Option<&str>) -> Result<Value, Error> {
+        let devices = match filter {
+            Some(name) => vec![self.device(name)?],
+            None => self.devices.iter().collect(),
+        };
+        let mut result = Map::new();
+        for device in devices {
+            let states = lock(&device.states);
+            let mut switches: Vec<(&String, Value)> = Vec::new();
+            for (name, control) in &device.config.controls {
+                let state = states.get(&control.key()).cloned().flatten();
+                match control.command() {
+                    Command::Switch(data) => switches.push((
+                        name,
+                        json!(switch_state(data.initial_state, state.as_ref())),
+                    )),
+                    // Cycles show the name of their state
+                    Command::Cycle(data) => {
+                        let position = state
+                            .and_then(|state| state.switch)
+                            .unwrap_or(data.initial())
+                            .position;
+                        switches.push((name, json!(data.states[position].name)));
+                    }
+                    _ => {}
+                }
+            }
+            switches.sort_by(|a, b| a.0.cmp(b.0));
+            result.insert(
+                device.config.device.clone(),
+                Value::Object(
+                    switches
+                        .into_iter()
+                        .map(|(name, state)| (name.clone(), state))
+                        .collect(),
+                ),
+            );
+        }
+        Ok(Value::Object(result))
+    }
+
+    // Runs a control as if it was pressed. Switches toggle, cycles step forward, and encoders
+    // need a value.
+    fn fire(&self, device: &str, control: &str, value: Option<u8>, reply: Sender<ControlResponse>) {
+        let mut released = vec![];
+        let prepared = self.device(device).and_then(|device| {
+            let data = match device.config.get_control(&control.to_string())?.command() {
+                Command::Switch(_) => match set_switch(device, control, None, &self.runtime)? {
+                    (data, InitialSwitchState::ON, others) => {
+                        released = others;
+                        data.on
+                    }
+                    (data, _, _) => data.off,
+                },
+                Command::Encoder(data) => match value {
+                    Some(_) => data.execute,
+                    None => {
+                        return Err(Error::msg(format!(
+                            "{} is an encoder, it needs a value to fire.",
+                            control
+                        )))
+                    }
+                },
+                Command::Trigger(data) => match value {
+                    Some(value) => data
+                        .command(value)
+                        .cloned()
+                        .ok_or(no_zone(control, Some(&value)))?,
+                    None => data.execute.ok_or(Error::msg(format!(
+                        "{} only has velocity zones, it needs a value to fire.",
+                        control
+                    )))?,
+                },
+                Command::Cycle(_) => step_cycle(device, control)?,
+            };
+            Ok(data)
+        });
+        let data = match prepared {
+            Ok(data) => data,
+            Err(error) => {
+                let _ = reply.send(ControlResponse::failure(&error));
+                return;
+            }
+        };
+
+        let control = control.to_string();
+        let runtime = self.runtime.clone();
+        let log = self.log;
+        thread::spawn(move || {
+            for (other, off) in &released {
+                match spawn_command(other, off, &None, &runtime, log) {
+                    Ok(command) => log.info(format!("Executed command {}", command).as_str()),
+                    Err(error) => log.error(&error.to_string()),
+                }
+            }
+            let response = match spawn_command(&control, &data, &value.as_ref(), &runtime, log) {
+                Ok(command) => {
+                    log.info(format!("Executed command {}", command).as_str());
+                    ControlResponse::success(Some(json!(command)))
+                }
+                Err(error) => {
+                    log.error(&error.to_string());
+                    ControlResponse::failure(&error)
+                }
+            };
+            let _ = reply.send(response);
+        });
+    }
+}
+
+// Position of a switch, which is its initial state until it's pressed, set or restored
+pub fn switch_state(initial: InitialSwitchState, state: Option<&KeyState>) -> InitialSwitchState {
+    state
+        .and_then(|state| state.switch)
+        .unwrap_or(SwitchState::from(initial))
+        .into()
+}
+
+fn restores(config: &Config) -> bool {
+    config.controls.values().any(|control| {
+        matches!(
+            control.command(),
+            Command::Switch(data) if data.initial_state == InitialSwitchState::Restore
+        )
+    })
+}
+
+// States for a device that starts with saved states. Switches set to `restore` start where they
+// were left, and encoders start from their last value, so the first turn after a restart already
+// counts as a change.
+pub fn restored_states(config: &Config, saved: &SavedDevice) -> HashMap<u8, Option<KeyState>> {
+    let mut states = initial_states(config);
+    let controls = config.get_controls_by_key();
+    for (name, control) in &config.controls {
+        let (detections, switch) = match control.command() {
+            Command::Switch(data) if data.initial_state == InitialSwitchState::Restore => {
+                match saved.switches.get(name) {
+                    Some(state) => (Vec::new(), Some(SwitchState::from(*state))),
+                    None => continue,
+                }
+            }
+            Command::Encoder(data) if data.source.is_none() => match saved.encoders.get(name) {
+                Some(value) => (vec![*value], None),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if let Ok(event) = on_key_event(control.key(), None, config, &controls, 0) {
+            let mut state = event.state;
+            state.detections = detections;
+            state.switch = switch;
+            states.insert(control.key(), Some(state));
+        }
+    }
+    states
+}
+
+// Steps a cycle forward without running its commands. Returns the command of the state it reached.
+fn step_cycle(device: &Device, control: &str) -> Result<CommandData, Error> {
+    let control_data = device.config.get_control(&control.to_string())?;
+    let data = match control_data.command() {
+        Command::Cycle(data) => data,
+        _ => return Err(Error::msg(format!("{} is not a cycle.", control))),
+    };
+    let key = control_data.key();
+
+    let mut states = lock(&device.states);
+    let mut key_state = match states.get(&key).cloned().flatten() {
+        Some(key_state) => key_state,
+        None => {
+            on_key_event(
+                key,
+                None,
+                &device.config,
+                &device.config.get_controls_by_key(),
+                0,
+            )?
+            .state
+        }
+    };
+    let state = key_state.switch.unwrap_or(data.initial()).next();
+    key_state.switch = Some(state);
+    states.insert(key, Some(key_state));
+    Ok(data.states[state.position].execute.clone())
+}
+
+// Moves a switch to `state`, or toggles it if there's none, without running its commands.
+// Returns its config and the state it was left in.
+// Switches of a group turned OFF by another one, with the `off` commands to execute
+type Released = Vec<(String, CommandData)>;
+
+fn set_switch(
+    device: &Device,
+    control: &str,
+    state: Option<InitialSwitchState>,
+    runtime: &Runtime,
+) -> Result<(Switch, InitialSwitchState, Released), Error> {
+    let control_data = device.config.get_control(&control.to_string())?;
+    let data = match control_data.command() {
+        Command::Switch(data) => data,
+        _ => return Err(Error::msg(format!("{} is not a switch.", control))),
+    };
+    let key = control_data.key();
+
+    let mut states = lock(&device.states);
+    let mut key_state = match states.get(&key).cloned().flatten() {
+        Some(key_state) => key_state,
+        None => {
+            on_key_event(
+                key,
+                None,
+                &device.config,
+                &device.config.get_controls_by_key(),
+                0,
+            )?
+            .state
+        }
+    };
+    let state = match state {
+        Some(InitialSwitchState::Restore) => {
+            return Err(Error::msg("A switch can only be set to ON or OFF."))
+        }
+        Some(state) => SwitchState::from(state),
+        // Firing a switch of a group turns it ON, like pressing it
+        None if data.group.is_some() => SwitchState::from(InitialSwitchState::ON),
+        None => key_state
+            .switch
+            .unwrap_or(SwitchState::from(data.initial_state))
+            .next(),
+    };
+    key_state.switch = Some(state);
+    runtime.remember(&device.config.device, &CommandKind::Switch, &key_state);
+    states.insert(key, Some(key_state));
+
+    let mut released = vec![];
+    if state.is_on() {
+        let controls = device.config.get_controls_by_key();
+        for (event, activation) in release_group(&mut states, key, &device.config, &controls)? {
+            runtime.remember(&device.config.device, &event.kind, &event.state);
+            if let Command::Switch(other) =
+                device.config.get_control(&event.state.control)?.command()
+            {
+                if activation.valid {
+                    released.push((event.state.control, other.off));
+                }
+            }
+        }
+    }
+    Ok((data, state.into(), released))
+}
+
+// What has to be connected, disconnected or reconnected to go from one config to another.
+// Devices are matched by name, so a renamed device is removed and added again.
+pub fn diff_configs(old: &[Config], new: &[Config]) -> Vec<DeviceChange> {
+    let mut changes: Vec<DeviceChange> = old
+        .iter()
+        .filter(|config| !new.iter().any(|other| other.device == config.device))
+        .map(|config| DeviceChange::Removed(config.device.clone()))
+        .collect();
+    for config in new {
+        match old.iter().find(|other| other.device == config.device) {
+            None => changes.push(DeviceChange::Added(config.clone())),
+            Some(other) if other != config => changes.push(DeviceChange::Changed(config.clone())),
+            Some(_) => {}
+        }
+    }
+    changes
 }
 
-fn handle_device(device: String, config: Config, log: Logger) -> Result<(), Error> {
+// States for the controls of a changed device. Controls that didn't change, and whose
+// thresholds didn't either, keep their state, like the current position of a switch.
+pub fn carried_states(
+    old: &Config,
+    states: &HashMap<u8, Option<KeyState>>,
+    new: &Config,
+) -> HashMap<u8, Option<KeyState>> {
+    let mut carried = initial_states(new);
+    if old.thresholds != new.thresholds {
+        return carried;
+    }
+    for (name, control) in &new.controls {
+        if old.controls.get(name) != Some(control) || control.pressure().is_some() {
+            continue;
+        }
+        if let Some(state) = states.get(&control.key()) {
+            carried.insert(control.key(), state.clone());
+        }
+    }
+    carried
+}
+
+fn initial_states(config: &Config) -> HashMap<u8, Option<KeyState>> {
+    config
+        .get_controls_by_key()
+        .into_keys()
+        .map(|key| (key, None))
+        .collect()
+}
+
+// Listens to a device, with the controls of `bank` if any
+fn connect(
+    source: Config,
+    bank: Option<String>,
+    states: HashMap<u8, Option<KeyState>>,
+    runtime: &Runtime,
+    log: Logger,
+) -> Result<Device, Error> {
     //FIXME:Patch check what's the deal with alsa_seq() leaking memory
 
     //TODO:Minor Add error handling in case of dropped connection or device error (maybe with a heartbeat? The midir lib sucks)
 
+    let config = source.with_bank(bank.as_deref());
     let controls = config.get_controls_by_key();
 
     log.trace("Gotr controls list indexed by key", &controls);
 
-    let mut states: HashMap<u8, Option<KeyState>> = HashMap::new();
-
-    for control in controls.clone() {
-        states.insert(control.0, None);
-    }
+    let states = Arc::new(Mutex::new(states));
 
     log.trace("State set and populated", &states);
 
     log.info("Opening connection...");
 
-    let conn = create_connection(&device, states, controls, config, log);
+    let connection = create_connection(
+        &config.device,
+        Arc::clone(&states),
+        controls,
+        config.clone(),
+        runtime.clone(),
+        log,
+    )
+    .inspect_err(|_| log.warn("Something went wrong. Connection closed."))?;
 
-    match conn {
-        Ok(_) => {
-            log.trace("Connection created correctly, starting main loop", "");
-            loop {}
-        }
-        Err(error) => {
-            log.warn("Something went wrong. Connection closed.");
-            return Err(error);
-        }
-    }
+    log.trace("Connection created correctly", "");
+
+    Ok(Device {
+        config,
+        source,
+        bank,
+        states,
+        connection,
+    })
+}
+
+// A callback that panicked while holding the lock leaves the data as it was, which is still usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
 fn create_connection(
     device: &String,
-    mut states: HashMap<u8, Option<KeyState>>,
+    states: States,
     controls: HashMap<u8, String>,
     config: Config,
+    runtime: Runtime,
     log: Logger,
 ) -> Result<MidiInputConnection<()>, Error> {
     let mut midi_input = MidiInput::new("Midiboard: Runtime")?;
@@ -104,100 +913,370 @@ fn create_connection(
         "",
     );
 
-    let port = util::get_input_port(&device, log)?;
-    match midi_input.connect(
-        &port.clone(),
-        &device,
-        move |_stamp, message, _| {
-            let key = message[1];
-            let value = message[2];
+    let virtual_port = config.virtual_port;
 
-            log.trace(
-                "Callback reached, testing if it's a valid control",
-                format!("key: {}, velocity: {}", key, value).as_str(),
-            );
+    let mut thru = match &config.passthrough {
+        Some(passthrough) => Some(open_passthrough(passthrough, log)?),
+        None => None,
+    };
+    let mut feedback = open_feedback(&config, log);
+    let back_keys = config.get_back_keys();
+    let config = Arc::new(config);
+    // Commands run on the worker of the device, after the states are unlocked
+    let (jobs, _) = start_worker(config.clone(), runtime.clone(), log);
 
-            match states.get(&key) {
-                Some(state) => {
-                    log.debug(
-                        format!("Control {} detected.", &controls.get(&key).unwrap()).as_str(),
-                    );
-                    log.trace("Testing for state initialization", &state);
-                    match on_key_event(key, state.clone(), &config, &controls, value) {
-                        Ok(mut key_event) => match key_event.initialized {
-                            true => {
-                                log.trace("State is initialized, starting debounce", &key_event);
-                                match debounce(&mut key_event, log) {
-                                    Ok(activation) => {
-                                        log.trace("Detection data passed", &activation);
-                                        if activation.valid {
-                                            log.trace("Activation valid, calling commands", "");
-                                            match call_command(
-                                                &key_event,
-                                                &activation,
-                                                &config.controls,
-                                                log,
-                                            ) {
-                                                Ok(command) => log.info(
-                                                    format!("Executed command {}", command)
-                                                        .as_str(),
-                                                ),
-                                                Err(error) => log.error(&error.to_string()),
-                                            };
-                                            log.trace(
-                                                "Managing current state",
-                                                &states.get(&key).unwrap(),
-                                            );
-                                            states.remove(&key);
-                                            match &key_event.kind {
-                                                CommandKind::Switch => {
-                                                    log.trace(
-                                                        "Event is from a Switch, state is kept",
-                                                        &key_event.state,
-                                                    );
-                                                    // Persist state for switches
-                                                    states.insert(key, Some(key_event.state));
-                                                }
-                                                _ => {
-                                                    log.trace("State is discarded", "");
-                                                    states.insert(key, None);
-                                                }
-                                            }
-                                        } else {
-                                            log.trace("Activation invalid", &activation);
-                                        }
-                                    }
-                                    Err(error) => log.error(&error.to_string()),
-                                }
-                            }
-                            false => {
-                                log.trace(
-                                    "State is not initialized, populating it",
-                                    &key_event.state,
-                                );
-                                states.remove(&key);
-                                states.insert(key, Some(key_event.state));
-                            }
-                        },
-                        Err(error) => log.error(&error.to_string()),
-                    };
+    let callback = move |_stamp: u64, message: &[u8], _: &mut ()| {
+        if runtime.stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        let decoded = MidiMessage::parse(message);
+        let data = decoded.and_then(|decoded| decoded.key_value());
+
+        if let (Some(connection), Some(passthrough), Some(decoded)) =
+            (&mut thru, &config.passthrough, &decoded)
+        {
+            let consumed = data.is_some_and(|(key, _)| {
+                lock(&states).contains_key(&key) || back_keys.contains_key(&key)
+            }) || !config.get_pressure_controls(decoded).is_empty();
+            if passthrough.should_forward(decoded, consumed) {
+                log.trace("Forwarding message to passthrough port", message);
+                if let Err(error) = connection.send(message) {
+                    log.warn(format!("Could not forward message: {}", error).as_str());
                 }
-                None => {
-                    log.trace("Not a valid control", "");
+            }
+        }
+
+        if let Some(
+            decoded @ MidiMessage {
+                kind: MessageType::PolyAftertouch | MessageType::ChannelPressure,
+                value: Some(value),
+                ..
+            },
+        ) = decoded
+        {
+            for control in config.get_pressure_controls(&decoded) {
+                if jobs.send(Job::Pressure { control, value }).is_err() {
+                    log.error("The commands of the device are not running anymore.");
                 }
             }
-        },
-        (),
-    ) {
+            return;
+        }
+
+        let (key, value) = match data {
+            Some(data) => data,
+            None => {
+                log.trace("Message carries no key or value, skipping it", message);
+                return;
+            }
+        };
+
+        log.trace(
+            "Callback reached, testing if it's a valid control",
+            format!("key: {}, velocity: {}", key, value).as_str(),
+        );
+
+        // The activation is decided under the lock, its commands run once it's released so
+        // they don't hold up the control socket, reloads or signals while they wait
+        let mut states = lock(&states);
+        let (key_event, activation) =
+            match process_key(&mut states, key, value, &config, &controls, log) {
+                Ok(Some(event)) => event,
+                Ok(None) => return,
+                Err(error) => return log.error(&error.to_string()),
+            };
+        // The rest of its group goes OFF before a switch goes ON
+        let released = match activation.kind {
+            Some(ActivationKind::Switch { on: true }) => {
+                release_group(&mut states, key, &config, &controls)
+            }
+            _ => Ok(vec![]),
+        };
+        let released = released.unwrap_or_else(|error| {
+            log.error(&error.to_string());
+            vec![]
+        });
+
+        drop(states);
+        for (event, _) in &released {
+            runtime.remember(&config.device, &event.kind, &event.state);
+        }
+        runtime.remember(&config.device, &key_event.kind, &key_event.state);
+        if let (Some(connection), Some(decoded)) = (&mut feedback, &decoded) {
+            send_feedback(connection, &config, &key_event, decoded, log);
+        }
+
+        log.trace("Activation valid, queueing its commands", "");
+        let job = Job::Key {
+            released,
+            event: key_event,
+            activation,
+        };
+        if jobs.send(job).is_err() {
+            log.error("The commands of the device are not running anymore.");
+        }
+    };
+
+    let connection = if virtual_port {
+        log.info(format!("Creating virtual input port {}", device).as_str());
+        midi_input.create_virtual(device, callback, ())
+    } else {
+        let port = util::get_input_port(device, log)?;
+        midi_input.connect(&port, device, callback, ())
+    };
+
+    match connection {
         Ok(connection) => Ok(connection),
         Err(error) => Err(Error::msg(error.kind().clone().to_string())),
     }
 }
 
+// Commands waiting for the worker of a device
+pub enum Job {
+    // An activation, after the switches of its group it turned OFF
+    Key {
+        released: Vec<(KeyEvent, Activation)>,
+        event: KeyEvent,
+        activation: Activation,
+    },
+    // A new pressure for a pressure control, which runs if its rate allows it
+    Pressure {
+        control: String,
+        value: u8,
+    },
+}
+
+// Runs the commands of a device one at a time, in the order its events came in, so a quick
+// tap runs `on` before `off` and the last value of an encoder is the one left. It stops once
+// every sender is gone, skipping what's left when shutting down.
+pub fn start_worker(
+    config: Arc<Config>,
+    runtime: Runtime,
+    log: Logger,
+) -> (Sender<Job>, thread::JoinHandle<()>) {
+    let (sender, jobs) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        let mut pressures = Pressures::new();
+        loop {
+            // Waits for the next job, or until a held back pressure is due
+            let job = match pressures
+                .values()
+                .filter_map(|pressure| pressure.held)
+                .min()
+            {
+                Some((due, _)) => {
+                    match jobs.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                        Ok(job) => Some(job),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match jobs.recv() {
+                    Ok(job) => Some(job),
+                    Err(_) => break,
+                },
+            };
+            if runtime.stopping.load(Ordering::Relaxed) {
+                pressures.clear();
+                continue;
+            }
+            match job {
+                Some(Job::Key {
+                    released,
+                    event,
+                    activation,
+                }) => {
+                    for (other, release) in &released {
+                        if release.valid {
+                            run_command(other, release, &config, &runtime, log);
+                        }
+                    }
+                    run_command(&event, &activation, &config, &runtime, log);
+                }
+                Some(Job::Pressure { control, value }) => {
+                    match on_pressure(&mut pressures, &control, value, &config, Instant::now()) {
+                        Ok(Some(value)) => run_pressure(&control, value, &config, &runtime, log),
+                        Ok(None) => log.trace("Pressure skipped", (&control, value)),
+                        Err(error) => log.error(&error.to_string()),
+                    }
+                }
+                None => {}
+            }
+            for (control, value) in send_held(&mut pressures, Instant::now()) {
+                run_pressure(&control, value, &config, &runtime, log);
+            }
+        }
+    });
+    (sender, worker)
+}
+
+// Opens the port LED feedback is sent to, when a control of the device has any.
+// Devices without an output port just go without it.
+fn open_feedback(config: &Config, log: Logger) -> Option<MidiOutputConnection> {
+    let wanted = config
+        .controls
+        .values()
+        .any(|control| match control.command() {
+            Command::Cycle(data) => data.states.iter().any(|state| state.feedback.is_some()),
+            _ => false,
+        });
+    if !wanted {
+        return None;
+    }
+    let name = config.feedback_output.as_ref().unwrap_or(&config.device);
+    let connection = MidiOutput::new("Midiboard: Feedback")
+        .map_err(Error::from)
+        .and_then(|output| {
+            let port = util::get_output_port(name, log)?;
+            output
+                .connect(&port, name)
+                .map_err(|error| Error::msg(error.kind().to_string()))
+        });
+    match connection {
+        Ok(connection) => {
+            log.info(format!("Sending LED feedback to {}", name).as_str());
+            Some(connection)
+        }
+        Err(error) => {
+            log.warn(format!("LED feedback is off, could not open {}: {}", name, error).as_str());
+            None
+        }
+    }
+}
+
+// Lights the key of a cycle with the feedback value of the state it reached, answering with
+// the kind of message and channel it was pressed with
+fn send_feedback(
+    connection: &mut MidiOutputConnection,
+    config: &Config,
+    event: &KeyEvent,
+    pressed: &MidiMessage,
+    log: Logger,
+) {
+    let control = match config.controls.get(&event.state.control) {
+        Some(control) => control,
+        None => return,
+    };
+    let value = match (control.command(), event.state.switch) {
+        (Command::Cycle(data), Some(state)) => data.states[state.position].feedback,
+        _ => None,
+    };
+    let (value, channel) = match (value, pressed.channel) {
+        (Some(value), Some(channel)) => (value, channel - 1),
+        _ => return,
+    };
+    let status = match pressed.kind {
+        MessageType::ControlChange => 0xB0,
+        _ => 0x90,
+    };
+    log.trace("Sending feedback", (control.key(), value));
+    if let Err(error) = connection.send(&[status | channel, control.key(), value]) {
+        log.warn(format!("Could not send feedback: {}", error).as_str());
+    }
+}
+
+fn open_passthrough(passthrough: &Passthrough, log: Logger) -> Result<MidiOutputConnection, Error> {
+    let midi_output = MidiOutput::new("Midiboard: Passthrough")?;
+
+    let connection = if passthrough.virtual_port {
+        log.info(format!("Creating virtual passthrough port {}", passthrough.output).as_str());
+        midi_output.create_virtual(&passthrough.output)
+    } else {
+        let port = util::get_output_port(&passthrough.output, log)?;
+        log.info(format!("Forwarding messages to {}", passthrough.output).as_str());
+        midi_output.connect(&port, &passthrough.output)
+    };
+
+    connection.map_err(|error| Error::msg(error.kind().to_string()))
+}
+
+// Decides whether a pressure control runs with a new pressure, at most once per activation
+// threshold. Repeated values are skipped, and a release to 0 always goes through, so what it
+// controls isn't left where the last pressure that made it was. A value that comes too soon is
+// held back, and the latest one held is sent by `send_held` when the threshold is over.
+pub fn on_pressure(
+    pressures: &mut Pressures,
+    control: &String,
+    value: u8,
+    config: &Config,
+    now: Instant,
+) -> Result<Option<u8>, Error> {
+    let control_data = config.get_control(control)?;
+    let interval = Duration::from_millis(match control_data.threshold() {
+        Some(Threshold::Base(threshold)) => threshold.activation,
+        Some(Threshold::Full(threshold)) => threshold.activation,
+        None => config.thresholds.encoder.activation,
+    });
+    let pressure = pressures.entry(control.clone()).or_default();
+    if let Some((sent, previous)) = pressure.sent {
+        if previous == value {
+            pressure.held = None;
+            return Ok(None);
+        }
+        if value > 0 && now < sent + interval {
+            pressure.held = Some((sent + interval, value));
+            return Ok(None);
+        }
+    }
+    pressure.sent = Some((now, value));
+    pressure.held = None;
+    Ok(Some(value))
+}
+
+// Pressures held back whose activation threshold is over by `now`, which run now
+pub fn send_held(pressures: &mut Pressures, now: Instant) -> Vec<(String, u8)> {
+    let mut due = Vec::new();
+    for (control, pressure) in pressures.iter_mut() {
+        if let Some((_, value)) = pressure.held.filter(|(at, _)| *at <= now) {
+            pressure.sent = Some((now, value));
+            pressure.held = None;
+            due.push((control.clone(), value));
+        }
+    }
+    due
+}
+
+fn run_pressure(control: &String, value: u8, config: &Config, runtime: &Runtime, log: Logger) {
+    let result =
+        config
+            .get_control(control)
+            .and_then(|control_data| match control_data.command() {
+                Command::Encoder(data) => {
+                    spawn_command(control, &data.execute, &Some(&value), runtime, log)
+                }
+                _ => Err(Error::msg(format!("{} is not an encoder.", control))),
+            });
+    match result {
+        Ok(command) => log.info(format!("Executed command {}", command).as_str()),
+        Err(error) => log.error(&error.to_string()),
+    }
+}
+
+fn no_zone(control: &str, velocity: Option<&u8>) -> Error {
+    Error::msg(format!(
+        "No velocity zone of {} covers {}, and it has no other command.",
+        control,
+        velocity.copied().unwrap_or_default()
+    ))
+}
+
+fn run_command(
+    event: &KeyEvent,
+    activation: &Activation,
+    config: &Config,
+    runtime: &Runtime,
+    log: Logger,
+) {
+    match call_command(event, activation, &config.controls, runtime, log) {
+        Ok(command) => log.info(format!("Executed command {}", command).as_str()),
+        Err(error) => log.error(&error.to_string()),
+    };
+}
+
 fn call_command(
     event: &KeyEvent,
     activation: &Activation,
     config_data: &ControlList,
+    runtime: &Runtime,
     log: Logger,
 ) -> Result<String, Error> {
     let command = &config_data
@@ -219,6 +1298,7 @@ fn call_command(
                         &event.state.control,
                         &data.execute,
                         &event.state.detections.last(),
+                        runtime,
                         log,
                     )
                 } else {
@@ -239,6 +1319,7 @@ fn call_command(
                         &event.state.control,
                         command_data,
                         &event.state.detections.last(),
+                        runtime,
                         log,
                     )
                 } else {
@@ -247,14 +1328,17 @@ fn call_command(
                     ));
                 }
             }
-            Command::Trigger(data) => {
-                if let ActivationKind::Trigger = activation_data {
-                    // Yes your eyes are correct this is now exactly the same as the encoder.
-                    // I am keeping it duplicated in case something changes and i have to again modify this.
+            Command::Cycle(data) => {
+                if let ActivationKind::Cycle { position } = activation_data {
+                    let state = data.states.get(*position).ok_or(Error::msg(format!(
+                        "{} has no state {}",
+                        event.state.control, position
+                    )))?;
                     spawn_command(
                         &event.state.control,
-                        &data.execute,
+                        &state.execute,
                         &event.state.detections.last(),
+                        runtime,
                         log,
                     )
                 } else {
@@ -263,6 +1347,19 @@ fn call_command(
                     ));
                 }
             }
+            Command::Trigger(data) => {
+                if let ActivationKind::Trigger = activation_data {
+                    let velocity = event.state.detections.last();
+                    let command = velocity
+                        .and_then(|velocity| data.command(*velocity))
+                        .ok_or(no_zone(&event.state.control, velocity))?;
+                    spawn_command(&event.state.control, command, &velocity, runtime, log)
+                } else {
+                    return Err(Error::msg(
+                        "Mismatched command types in activation and config at command call",
+                    ));
+                }
+            }
         }
     } else {
         Err(Error::msg(
@@ -271,10 +1368,11 @@ fn call_command(
     }
 }
 
-fn spawn_command(
+pub fn spawn_command(
     control: &String,
     data: &CommandData,
     value: &Option<&u8>,
+    runtime: &Runtime,
     log: Logger,
 ) -> Result<String, Error> {
     let args: Vec<String>;
@@ -317,25 +1415,50 @@ fn spawn_command(
         args = data.args.clone();
         cmd = data.cmd.clone();
     };
+    // The raw value goes in as it came, next to or instead of the mapped one
+    let (cmd, args) = match (&data.velocity, value) {
+        (Some(_), None) => {
+            return Ok("No value registered for a command that required one.".to_string());
+        }
+        (Some(placeholder), Some(value)) => (
+            cmd.replace(placeholder, &value.to_string()),
+            args.iter()
+                .map(|arg| arg.replace(placeholder, &value.to_string()))
+                .collect(),
+        ),
+        (None, _) => (cmd, args),
+    };
 
-    let mut cmd_data = HashMap::new();
-
-    cmd_data.insert("cmd", vec![cmd.clone()]);
-    cmd_data.insert("args", args.clone());
+    // Commands can have secrets filled in from the environment or files, they are not logged
+    log.trace("Running the command of", control);
 
-    log.trace("COMMAND DATA:", cmd_data);
+    // The child is registered while it runs, so shutting down can wait for it or stop it
+    let mut child = process::Command::new(cmd)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let id = child.id();
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    lock(&runtime.children).insert(id, child);
 
-    let child = process::Command::new(cmd).args(args).output()?;
+    let stderr_reader = thread::spawn(move || read_output(stderr));
+    let stdout = read_output(stdout);
+    let stderr = stderr_reader.join().unwrap_or_default();
+    let status = match lock(&runtime.children).remove(&id) {
+        Some(mut child) => child.wait()?,
+        None => return Err(Error::msg(format!("{} was lost while running.", control))),
+    };
 
-    if child.stdout.len() > 0 {
-        log.message(from_utf8(child.stdout.as_slice())?, data.cmd.as_str());
+    if stdout.len() > 0 {
+        log.message(from_utf8(stdout.as_slice())?, data.cmd.as_str());
     }
 
-    if child.stderr.len() > 0 {
-        log.message(from_utf8(child.stderr.as_slice())?, data.cmd.as_str());
+    if stderr.len() > 0 {
+        log.message(from_utf8(stderr.as_slice())?, data.cmd.as_str());
     }
 
-    let success = child.status.success();
+    let success = status.success();
 
     if success {
         return Ok(format!("{} successfully.", control));
@@ -344,6 +1467,165 @@ fn spawn_command(
     }
 }
 
+fn read_output<R: Read>(pipe: Option<R>) -> Vec<u8> {
+    let mut output = Vec::new();
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_end(&mut output);
+    }
+    output
+}
+
+// Runs a message for `key` through the state of its control. Returns the event and its
+// activation when it should run a command. Switches keep their state after an activation,
+// everything else starts over.
+pub fn process_key(
+    states: &mut HashMap<u8, Option<KeyState>>,
+    key: u8,
+    value: u8,
+    config: &Config,
+    controls: &ControlListByKey,
+    log: Logger,
+) -> Result<Option<(KeyEvent, Activation)>, Error> {
+    // The back key of a cycle shares the state of the cycle's own key
+    let (key, back_key) = match config.get_back_keys().get(&key) {
+        Some(cycle_key) => (*cycle_key, true),
+        None => (key, false),
+    };
+    let state = match states.get(&key) {
+        Some(state) => state.clone(),
+        None => {
+            log.trace("Not a valid control", "");
+            return Ok(None);
+        }
+    };
+    log.debug(format!("Control {} detected.", &controls[&key]).as_str());
+    log.trace("Testing for state initialization", &state);
+
+    let mut key_event = on_key_event(key, state, config, controls, value)?;
+    match config.get_control(&controls[&key])?.command() {
+        Command::Cycle(cycle) => key_event.backward = back_key || cycle.steps_back(value),
+        Command::Switch(Switch {
+            momentary: true, ..
+        }) => return Ok(hold(states, key, key_event, value > 0, log)),
+        _ => {}
+    }
+    if !key_event.initialized {
+        log.trace("State is not initialized, populating it", &key_event.state);
+        states.insert(key, Some(key_event.state));
+        return Ok(None);
+    }
+
+    log.trace("State is initialized, starting debounce", &key_event);
+    let activation = debounce(&mut key_event, log)?;
+    if !activation.valid {
+        log.trace("Activation invalid", &activation);
+        return Ok(None);
+    }
+    log.trace("Detection data passed", &activation);
+    // A switch of a group stays ON when pressed again, like a radio button
+    if let (Some(ActivationKind::Switch { on: false }), Some(_)) =
+        (&activation.kind, switch_group(config, &controls[&key])?)
+    {
+        log.trace("Switch is already ON in its group, keeping it", "");
+        key_event.state.switch = Some(SwitchState::from(InitialSwitchState::ON));
+        states.insert(key, Some(key_event.state));
+        return Ok(None);
+    }
+    match &key_event.kind {
+        CommandKind::Switch | CommandKind::Cycle | CommandKind::Trigger => {
+            log.trace(
+                "Event is from a Switch or Trigger, state is kept",
+                &key_event.state,
+            );
+            states.insert(key, Some(key_event.state.clone()));
+        }
+        _ => {
+            log.trace("State is discarded", "");
+            states.insert(key, None);
+        }
+    }
+    Ok(Some((key_event, activation)))
+}
+
+// A momentary switch follows presses and releases as they come, without debouncing. Repeated
+// presses or releases don't change it.
+fn hold(
+    states: &mut HashMap<u8, Option<KeyState>>,
+    key: u8,
+    mut key_event: KeyEvent,
+    pressed: bool,
+    log: Logger,
+) -> Option<(KeyEvent, Activation)> {
+    let held = key_event.state.switch.is_some_and(|state| state.is_on());
+    key_event.state.detections = key_event.state.detections.pop().into_iter().collect();
+    if held == pressed {
+        log.trace(
+            "Momentary switch is already in that state",
+            &key_event.state,
+        );
+        states.insert(key, Some(key_event.state));
+        return None;
+    }
+    key_event.initialized = true;
+    key_event.state.start = Instant::now();
+    key_event.state.switch = Some(SwitchState::from(match pressed {
+        true => InitialSwitchState::ON,
+        false => InitialSwitchState::OFF,
+    }));
+    states.insert(key, Some(key_event.state.clone()));
+    Some((key_event, Activation::switch(true, pressed)))
+}
+
+fn switch_group(config: &Config, control: &String) -> Result<Option<String>, Error> {
+    Ok(match config.get_control(control)?.command() {
+        Command::Switch(data) => data.group,
+        _ => None,
+    })
+}
+
+// Turns OFF the other switches of the group of `key`, which just turned ON. Switches that go OFF
+// silently come with an invalid activation, their state is only to be remembered.
+pub fn release_group(
+    states: &mut HashMap<u8, Option<KeyState>>,
+    key: u8,
+    config: &Config,
+    controls: &ControlListByKey,
+) -> Result<Vec<(KeyEvent, Activation)>, Error> {
+    let group = match switch_group(config, &controls[&key])? {
+        Some(group) => group,
+        None => return Ok(vec![]),
+    };
+    let mut released = vec![];
+    for control in config.controls.values() {
+        let other = control.key();
+        let data = match control.command() {
+            Command::Switch(data) if other != key && data.group.as_ref() == Some(&group) => data,
+            _ => continue,
+        };
+        let current = states.get(&other).cloned().flatten();
+        if switch_state(data.initial_state, current.as_ref()) != InitialSwitchState::ON {
+            continue;
+        }
+        let mut state = match current {
+            Some(state) => state,
+            None => on_key_event(other, None, config, controls, 0)?.state,
+        };
+        state.switch = Some(SwitchState::from(InitialSwitchState::OFF));
+        states.insert(other, Some(state.clone()));
+        released.push((
+            KeyEvent {
+                initialized: true,
+                state,
+                kind: CommandKind::Switch,
+                elapsed: None,
+                backward: false,
+            },
+            Activation::switch(!data.group_silent, false),
+        ));
+    }
+    Ok(released)
+}
+
 fn on_key_event(
     key: u8,
     state: Option<KeyState>,
@@ -396,15 +1678,19 @@ fn on_key_event(
                         },
                         detections: Vec::new(),
                         start: Instant::now(),
-                        initial_state: match command_data {
-                            Command::Switch(data) => Some(data.initial_state),
+                        switch: match command_data {
+                            Command::Switch(data) => Some(SwitchState::from(data.initial_state)),
+                            Command::Cycle(data) => Some(data.initial()),
                             _ => None,
                         },
                     };
                     new_state.detections.push(value);
 
+                    // Switches move and triggers fire on their very first press, encoders need
+                    // something to compare with
                     return Ok(KeyEvent {
-                        initialized: false,
+                        initialized: threshold_data.0 != CommandKind::Encoder,
+                        backward: false,
                         state: new_state,
                         kind: threshold_data.0,
                         elapsed: None,
@@ -416,6 +1702,7 @@ fn on_key_event(
 
                     return Ok(KeyEvent {
                         initialized: true,
+                        backward: false,
                         state: new_state,
                         kind: threshold_data.0,
                         elapsed: Some(Instant::now().duration_since(state.start)),
@@ -429,7 +1716,8 @@ fn on_key_event(
 fn debounce(event: &mut KeyEvent, log: Logger) -> Result<Activation, Error> {
     let activation_threshold = event.state.activation_threshold;
     let time_threshold = event.state.detection_threshold;
-    let elapsed = event.elapsed.unwrap();
+    // Only switches and triggers get here without a previous detection
+    let elapsed = event.elapsed.unwrap_or_default();
 
     // TODO:Minor Add proportional reading of increases to actually modify data using percentuals
     // TODO:Minor Add easing to the controls reaction
@@ -487,70 +1775,58 @@ fn debounce(event: &mut KeyEvent, log: Logger) -> Result<Activation, Error> {
                 }
             }
         }
-        CommandKind::Switch => {
-            if elapsed.gt(&activation_threshold) {
-                // Reset elapsed time
-                event.state.start = Instant::now();
-                event.elapsed = Some(Duration::from_millis(0));
-
-                if event.state.detections.len() == 2 {
-                    // HACK:Minor we use 255 as OFF and anything else as ON.
-                    // We can do that because the MIDI lib only supports MIDI 1.0, which limits velocities to 7 bits.
-
-                    if let Some(initial_state) = event.state.initial_state {
-                        // This is first activation so we gotta check the initial state in the config
-                        match initial_state {
-                            InitialSwitchState::OFF => {
-                                event.state.detections = vec![255, 255];
-                                Activation::switch(true, false).as_ok()
-                            }
-                            InitialSwitchState::ON => {
-                                event.state.detections = vec![200, 200]; // 200 is an arbitrary choice, it does not matter.
-                                Activation::switch(true, false).as_ok()
-                            }
-                        }
-                    } else {
-                        Err(Error::msg(format!(
-                            "Initial state for control {} not found in the config.",
-                            event.state.control
-                        )))
-                    }
-                } else {
-                    let is_currently_on: bool;
-                    event.state.detections.pop(); //remove actual new value
-                                                  // redefine detections to represent states
-                    if event.state.detections.last().unwrap() == &255 {
-                        is_currently_on = false;
-                        event.state.detections.push(200); // set as now on
-                    } else {
-                        is_currently_on = true;
-                        event.state.detections.push(255); // Set as now off
-                    }
-
-                    // ? truncates the vec if it's too large, to avoid massive potential leaks (the MIDI lib closure possible is leaking this) on long run times
-                    if event.state.detections.len() > 50 {
-                        event.state.detections =
-                            event.state.detections[event.state.detections.len() - 3..].to_vec()
-                    }
+        CommandKind::Switch | CommandKind::Cycle => {
+            // Only the value of the last press is kept, the position is in the switch state
+            let value = event.state.detections.pop();
+            // Only presses move them, the release that follows is not another one
+            if value == Some(0) {
+                log.trace("Switch debounce: Release ignored", &event);
+                return Activation::failed().as_ok();
+            }
+            // The first press has no previous one to be too close to
+            if event
+                .elapsed
+                .is_some_and(|elapsed| elapsed.le(&activation_threshold))
+            {
+                return Activation::failed().as_ok();
+            }
+            event.state.start = Instant::now();
+            event.elapsed = Some(Duration::from_millis(0));
+            event.state.detections = value.into_iter().collect();
 
-                    Activation::switch(true, !is_currently_on).as_ok()
-                }
-            } else {
-                event.state.detections.pop();
-                Activation::failed().as_ok()
+            let state = event.state.switch.ok_or(Error::msg(format!(
+                "Initial state for control {} not found in the config.",
+                event.state.control
+            )))?;
+            let state = match event.backward {
+                true => state.previous(),
+                false => state.next(),
+            };
+            event.state.switch = Some(state);
+            match event.kind {
+                CommandKind::Cycle => Activation::cycle(true, state.position).as_ok(),
+                _ => Activation::switch(true, state.is_on()).as_ok(),
             }
         }
         CommandKind::Trigger => {
-            if elapsed.gt(&activation_threshold) {
-                // Reset elapsed time and detections
-                event.state.start = Instant::now();
-                event.state.detections = Vec::new();
-                event.elapsed = Some(Duration::from_millis(0));
-
-                Activation::trigger(true).as_ok()
-            } else {
-                Activation::failed().as_ok()
+            // It fires on the hit with its velocity, the release after it is not another hit
+            let value = event.state.detections.pop();
+            if value == Some(0) {
+                log.trace("Trigger debounce: Release ignored", &event);
+                return Activation::failed().as_ok();
             }
+            // Hits too close to the last one that fired are ignored
+            if event
+                .elapsed
+                .is_some_and(|elapsed| elapsed.le(&activation_threshold))
+            {
+                return Activation::failed().as_ok();
+            }
+            event.state.start = Instant::now();
+            event.elapsed = Some(Duration::from_millis(0));
+            event.state.detections = value.into_iter().collect();
+
+            Activation::trigger(true).as_ok()
         }
     }
 }
@@ -0,0 +1,138 @@
+use super::types::{InitialSwitchState, SavedDevice, SavedStates};
+use super::util::Logger;
+use anyhow::Error;
+use home::home_dir;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How long changes are gathered before the file is written, so a turning knob writes it once
+const WRITE_DELAY: Duration = Duration::from_millis(500);
+
+// Switch states and encoder values kept between runs of the service
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    saved: Mutex<SavedStates>,
+    // Set while changes wait to be written
+    pending: AtomicBool,
+    // Held while writing, so two writes never overlap
+    writing: Mutex<()>,
+    log: Logger,
+}
+
+// `$XDG_STATE_HOME/midiboard/state.json`, with `~/.local/state` if it's unset
+pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".local").join("state")))?;
+    Some(dir.join("midiboard").join("state.json"))
+}
+
+impl StateFile {
+    // Reads the state file. A missing one is fine, and an unreadable one is started over.
+    pub fn open(path: PathBuf, log: Logger) -> Arc<Self> {
+        let saved = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|error| {
+                log.warn(
+                    format!(
+                        "The state file {} is not valid, starting from scratch: {}",
+                        path.display(),
+                        error
+                    )
+                    .as_str(),
+                );
+                SavedStates::new()
+            }),
+            Err(_) => SavedStates::new(),
+        };
+        Arc::new(StateFile {
+            path,
+            saved: Mutex::new(saved),
+            pending: AtomicBool::new(false),
+            writing: Mutex::new(()),
+            log,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn device(&self, name: &str) -> SavedDevice {
+        self.lock().get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn save_switch(self: &Arc<Self>, device: &str, control: &str, state: InitialSwitchState) {
+        self.update(device, |saved| {
+            saved.switches.insert(control.to_string(), state) != Some(state)
+        })
+    }
+
+    pub fn save_encoder(self: &Arc<Self>, device: &str, control: &str, value: u8) {
+        self.update(device, |saved| {
+            saved.encoders.insert(control.to_string(), value) != Some(value)
+        })
+    }
+
+    // Applies `change` and, if anything changed, writes the file from another thread a little
+    // later. Changes made in the meantime are written along with it.
+    fn update<F: FnOnce(&mut SavedDevice) -> bool>(self: &Arc<Self>, device: &str, change: F) {
+        if !change(self.lock().entry(device.to_string()).or_default()) {
+            return;
+        }
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let state_file = Arc::clone(self);
+        thread::spawn(move || {
+            thread::sleep(WRITE_DELAY);
+            if let Err(error) = state_file.flush() {
+                state_file.log.warn(
+                    format!(
+                        "Could not write the state file {}: {}",
+                        state_file.path.display(),
+                        error
+                    )
+                    .as_str(),
+                );
+            }
+        });
+    }
+
+    // Writes the changes waiting to be written, if any
+    pub fn flush(&self) -> Result<(), Error> {
+        let _writing = self
+            .writing
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !self.pending.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let saved = self.lock().clone();
+        write(&self.path, &saved)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SavedStates> {
+        self.saved
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Writes to a temporary file next to the state file and renames it over, so a crash halfway
+// never leaves a truncated file behind
+fn write(path: &Path, saved: &SavedStates) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(saved)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
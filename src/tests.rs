@@ -13,9 +13,10 @@ fn read_user_config_paths() {
     let config_path = dir.path().join("midiboard.json");
     let _file = write(&config_path, skeleton).unwrap();
 
-    let read_config = util::read_user_config(Some(&String::from(
-        config_path.as_os_str().to_str().unwrap(),
-    )))
+    let read_config = util::read_user_config(
+        Some(&String::from(config_path.as_os_str().to_str().unwrap())),
+        None,
+    )
     .unwrap();
 
     let parsed_config = config_from_str(skeleton);
@@ -39,6 +40,61 @@ fn config_from_str(config_str: &str) -> types::ConfigFile {
     config_asset.try_deserialize::<types::ConfigFile>().unwrap()
 }
 
+// Config file with a single "Midi Controller" device and the given controls, `switch` being
+// the activation threshold of its switches and cycles
+fn device_config(switch: u64, controls: &str) -> String {
+    format!(
+        r#"{{
+    "log_level": "Error",
+    "config": [{{
+        "device": "Midi Controller",
+        "thresholds": {{
+            "encoder": {{"activation": 200, "detection": 50}},
+            "switch": {{"activation": {}}},
+            "trigger": {{"activation": 0}}
+        }},
+        "controls": {{{}
+        }}
+    }}]
+}}"#,
+        switch, controls
+    )
+}
+
+fn device_from(contents: &str) -> types::Config {
+    config_from_str(contents).config.remove(0)
+}
+
+// A device that is fed keys the way its connection does, keeping the states of its controls
+struct TestDevice {
+    config: types::Config,
+    states: std::collections::HashMap<u8, Option<types::KeyState>>,
+}
+
+impl TestDevice {
+    fn new(contents: &str) -> Self {
+        let config = device_from(contents);
+        let states = config
+            .get_controls_by_key()
+            .into_keys()
+            .map(|key| (key, None))
+            .collect();
+        TestDevice { config, states }
+    }
+
+    // The event and activation a key causes, if any
+    fn process(&mut self, key: u8, value: u8) -> Option<(types::KeyEvent, types::Activation)> {
+        let log = util::Logger::new(types::LogLevel::Error);
+        let controls = self.config.get_controls_by_key();
+        run::process_key(&mut self.states, key, value, &self.config, &controls, log).unwrap()
+    }
+
+    fn press(&mut self, key: u8, value: u8) -> Option<types::ActivationKind> {
+        self.process(key, value)
+            .and_then(|(_, activation)| activation.kind)
+    }
+}
+
 #[test]
 fn ease_input_test() {
     let runs = 5;
@@ -88,3 +144,1575 @@ fn ease_input_test() {
         );
     }
 }
+
+#[test]
+fn midi_message_parsing() {
+    let note_on = types::MidiMessage::parse(&[0x91, 36, 100]).unwrap();
+    assert_eq!(note_on.kind, types::MessageType::NoteOn);
+    assert_eq!(note_on.channel, Some(2));
+    assert_eq!(note_on.number, Some(36));
+    assert_eq!(note_on.value, Some(100));
+
+    let released = types::MidiMessage::parse(&[0x90, 36, 0]).unwrap();
+    assert_eq!(released.kind, types::MessageType::NoteOff);
+
+    let pressure = types::MidiMessage::parse(&[0xD0, 64]).unwrap();
+    assert_eq!(pressure.kind, types::MessageType::ChannelPressure);
+    assert_eq!(pressure.number, None);
+    assert_eq!(pressure.value, Some(64));
+
+    let clock = types::MidiMessage::parse(&[0xF8]).unwrap();
+    assert_eq!(clock.kind, types::MessageType::System);
+    assert_eq!(clock.channel, None);
+
+    assert_eq!(types::MidiMessage::parse(&[]), None);
+    assert_eq!(types::MidiMessage::parse(&[36, 100]), None);
+}
+
+#[test]
+fn passthrough_filters() {
+    let passthrough = types::Passthrough {
+        output: String::from("Thru"),
+        virtual_port: true,
+        forward: types::ForwardMode::Unconsumed,
+        include: vec![types::MessageFilter {
+            types: Some(vec![
+                types::MessageType::NoteOn,
+                types::MessageType::NoteOff,
+            ]),
+            channels: None,
+            keys: None,
+        }],
+        exclude: vec![types::MessageFilter {
+            types: None,
+            channels: Some(types::ValueRange { min: 10, max: 10 }),
+            keys: None,
+        }],
+    };
+
+    let note = types::MidiMessage::parse(&[0x90, 60, 90]).unwrap();
+    let drum = types::MidiMessage::parse(&[0x99, 36, 90]).unwrap();
+    let knob = types::MidiMessage::parse(&[0xB0, 7, 90]).unwrap();
+
+    assert!(passthrough.should_forward(&note, false));
+    assert!(!passthrough.should_forward(&note, true));
+    assert!(!passthrough.should_forward(&drum, false));
+    assert!(!passthrough.should_forward(&knob, false));
+
+    let all = types::Passthrough {
+        forward: types::ForwardMode::All,
+        include: Vec::new(),
+        ..passthrough
+    };
+    assert!(all.should_forward(&note, true));
+    assert!(all.should_forward(&knob, true));
+}
+
+#[test]
+fn port_info_parsing() {
+    let port = types::PortInfo::parse(
+        "nanoKONTROL2:nanoKONTROL2 MIDI 1 24:0",
+        types::PortDirection::Input,
+    );
+    assert_eq!(port.client, "nanoKONTROL2");
+    assert_eq!(port.port, "nanoKONTROL2 MIDI 1");
+    assert_eq!(port.client_id, Some(24));
+    assert_eq!(port.port_id, Some(0));
+
+    let bare = types::PortInfo::parse("Some Port", types::PortDirection::Output);
+    assert_eq!(bare.client, "Some Port");
+    assert_eq!(bare.client_id, None);
+}
+
+#[test]
+fn monitor_filter_arguments() {
+    use std::str::FromStr;
+
+    assert_eq!(
+        types::MessageType::from_str("note-on").unwrap(),
+        types::MessageType::NoteOn
+    );
+    assert_eq!(
+        types::MessageType::from_str("ControlChange").unwrap(),
+        types::MessageType::ControlChange
+    );
+    assert!(types::MessageType::from_str("sysex").is_err());
+
+    assert_eq!(
+        types::ValueRange::from_str("10").unwrap(),
+        types::ValueRange { min: 10, max: 10 }
+    );
+    assert_eq!(
+        types::ValueRange::from_str("1-9").unwrap(),
+        types::ValueRange { min: 1, max: 9 }
+    );
+    assert!(types::ValueRange::from_str("9-1").is_err());
+    assert_eq!(
+        types::ValueRange::channels("1-16").unwrap(),
+        types::ValueRange { min: 1, max: 16 }
+    );
+    assert!(types::ValueRange::channels("0").is_err());
+    assert!(types::ValueRange::channels("200").is_err());
+    assert!(types::ValueRange::channels("9-1").is_err());
+    let channel = |range: &str| {
+        cli().try_get_matches_from(["midiboard", "devices", "--input", "x", "--channel", range])
+    };
+    assert!(channel("2-3").is_ok());
+    assert!(channel("17").is_err());
+
+    let knob = types::MidiMessage::parse(&[0xB0, 7, 90]).unwrap();
+    let (name, control) = devices::control_snippet(&knob, None).unwrap();
+    assert_eq!(name, "cc_7");
+    assert_eq!(control["key"], 7);
+    assert_eq!(control["command"]["kind"], "Encoder");
+}
+
+#[test]
+fn config_document_insert_control() {
+    let dir = tempdir().unwrap();
+    let mut asset = types::Asset::get("midiboard.json").unwrap();
+    let skeleton = from_utf8(asset.data.to_mut().as_slice()).unwrap();
+    let config_path = dir.path().join("midiboard.json");
+    write(&config_path, skeleton).unwrap();
+
+    let mut document =
+        document::ConfigDocument::load(&config_path, types::ConfigFormat::Json).unwrap();
+
+    let control = serde_json::json!({
+        "key": 9,
+        "command": { "kind": "Trigger", "execute": { "cmd": "true", "args": [] } }
+    });
+    assert!(document
+        .insert_control("Your Device Here", "pause", control.clone())
+        .is_err());
+    assert!(document
+        .insert_control("yourdevicehere", "mute", control)
+        .is_ok());
+    let duplicated_key = serde_json::json!({
+        "key": 9,
+        "command": { "kind": "Trigger", "execute": { "cmd": "true", "args": [] } }
+    });
+    assert!(document
+        .insert_control("Your Device Here", "other", duplicated_key)
+        .is_err());
+    document.save().unwrap();
+
+    // Everything but the new control is kept as it was written
+    let written = std::fs::read_to_string(&config_path).unwrap();
+    let insertion = written.find(",\n                \"mute\"").unwrap();
+    assert_eq!(&written[..insertion], &skeleton[..insertion]);
+    assert!(written.contains("\"args\": [\"play-pause\"]"));
+    let read_config = util::read_user_config(
+        Some(&String::from(config_path.as_os_str().to_str().unwrap())),
+        None,
+    )
+    .unwrap();
+    assert_eq!(read_config.config[0].controls["mute"].key(), 9);
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn learn_guesses_control_kind() {
+    let note = |stamp: u64, status: u8, value: u8| {
+        (
+            stamp,
+            types::MidiMessage::parse(&[status, 36, value]).unwrap(),
+        )
+    };
+    let cc = |stamp: u64, value: u8| (stamp, types::MidiMessage::parse(&[0xB0, 7, value]).unwrap());
+
+    let tap = [note(0, 0x90, 100), note(80_000, 0x80, 0)];
+    assert_eq!(learn::guess_kind(&tap).kind, types::CommandKind::Trigger);
+
+    let hold = [note(0, 0x90, 100), note(900_000, 0x90, 0)];
+    assert_eq!(learn::guess_kind(&hold).kind, types::CommandKind::Switch);
+
+    let sweep: Vec<(u64, types::MidiMessage)> = (0..40)
+        .map(|step| cc(step * 10_000, step as u8 * 3))
+        .collect();
+    let guess = learn::guess_kind(&sweep);
+    assert_eq!(guess.kind, types::CommandKind::Encoder);
+    assert!(!guess.relative);
+
+    let relative = [cc(0, 1), cc(10_000, 1), cc(20_000, 127), cc(30_000, 1)];
+    let guess = learn::guess_kind(&relative);
+    assert_eq!(guess.kind, types::CommandKind::Encoder);
+    assert!(guess.relative);
+
+    let button = [cc(0, 127), cc(50_000, 0)];
+    assert_eq!(learn::guess_kind(&button).kind, types::CommandKind::Trigger);
+
+    let (message, _) = learn::detect(&[cc(0, 10), cc(1, 11), note(2, 0x90, 1)]).unwrap();
+    assert_eq!(message.kind, types::MessageType::ControlChange);
+}
+
+#[test]
+fn config_new_and_add_device() {
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("midiboard.json");
+    let path_arg = String::from(config_path.as_os_str().to_str().unwrap());
+
+    crate::config::generate_config(&config_path, types::ConfigFormat::Json, false).unwrap();
+    // Existing files are never overwritten
+    assert!(crate::config::generate_config(&config_path, types::ConfigFormat::Json, true).is_err());
+    assert!(util::read_user_config(Some(&path_arg), None)
+        .unwrap()
+        .config
+        .is_empty());
+
+    let mut document =
+        document::ConfigDocument::load(&config_path, types::ConfigFormat::Json).unwrap();
+    let entry = serde_json::json!({
+        "device": "Arturia Beatstep",
+        "thresholds": {
+            "encoder": { "activation": 300, "detection": 40 },
+            "switch": { "activation": 400 },
+            "trigger": { "activation": 1000 }
+        },
+        "controls": {}
+    });
+    assert!(prompts::check::<types::Config>(&entry).is_ok());
+    document
+        .set(
+            &[
+                document::Segment::Key(String::from("config")),
+                document::Segment::Index(0),
+            ],
+            &entry,
+        )
+        .unwrap();
+    document.save().unwrap();
+
+    let read_config = util::read_user_config(Some(&path_arg), None).unwrap();
+    assert_eq!(read_config.config[0].device, "Arturia Beatstep");
+    assert!(document.device_index("arturia beatstep").is_ok());
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_document_set_and_remove() {
+    let dir = tempdir().unwrap();
+    let mut asset = types::Asset::get("midiboard.json").unwrap();
+    let skeleton = from_utf8(asset.data.to_mut().as_slice()).unwrap();
+    let config_path = dir.path().join("midiboard.json");
+    write(&config_path, skeleton).unwrap();
+
+    let mut document =
+        document::ConfigDocument::load(&config_path, types::ConfigFormat::Json).unwrap();
+    let key = document::parse_path("config[0].controls.volume.key").unwrap();
+    assert_eq!(
+        key,
+        vec![
+            document::Segment::Key(String::from("config")),
+            document::Segment::Index(0),
+            document::Segment::Key(String::from("controls")),
+            document::Segment::Key(String::from("volume")),
+            document::Segment::Key(String::from("key")),
+        ]
+    );
+    assert!(document::parse_path("config[x]").is_err());
+
+    document.set(&key, &serde_json::json!(7)).unwrap();
+    assert_eq!(document.get(&key), Some(&serde_json::json!(7)));
+    document.save().unwrap();
+
+    // Out of range keys are rejected when saving and the file is left alone
+    document.set(&key, &serde_json::json!(700)).unwrap();
+    assert!(document.save().is_err());
+    let written = std::fs::read_to_string(&config_path).unwrap();
+    assert!(written.contains("\"key\": 7,"));
+
+    let mut document =
+        document::ConfigDocument::load(&config_path, types::ConfigFormat::Json).unwrap();
+    assert!(document
+        .remove_control("Your Device Here", "missing")
+        .is_err());
+    document
+        .remove_control("Your Device Here", "volume")
+        .unwrap();
+    document.save().unwrap();
+
+    let written = std::fs::read_to_string(&config_path).unwrap();
+    assert!(written.starts_with(&skeleton[..skeleton.find("\"log_level\"").unwrap()]));
+    assert!(!written.contains("\"volume\""));
+    let read_config = util::read_user_config(
+        Some(&String::from(config_path.as_os_str().to_str().unwrap())),
+        None,
+    )
+    .unwrap();
+    assert!(!read_config.config[0].controls.contains_key("volume"));
+
+    document.remove_device("yourdevicehere").unwrap();
+    assert_eq!(
+        document.get(&document::parse_path("config").unwrap()),
+        Some(&serde_json::json!([]))
+    );
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_validation_diagnostics() {
+    let path = std::path::Path::new("midiboard.json");
+    let mut asset = types::Asset::get("midiboard.json").unwrap();
+    let skeleton = from_utf8(asset.data.to_mut().as_slice()).unwrap();
+    let errors = |diagnostics: &[validate::Diagnostic]| {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == validate::Severity::Error)
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect::<Vec<String>>()
+    };
+
+    assert!(errors(&validate::check_text(path, skeleton)).is_empty());
+
+    let syntax = validate::check_text(path, "{\n    \"config\": [\n}");
+    assert_eq!((syntax[0].line, syntax[0].column), (3, 1));
+
+    let broken = r#"{
+    "log_level": "Warn",
+    "config": [
+        {
+            "device": "Test",
+            "thresholds": {
+                "encoder": { "activation": 300, "detection": 300 },
+                "switch": { "activation": 400 },
+                "trigger": { "activation": 1000 }
+            },
+            "controls": {
+                "first": {
+                    "key": 7,
+                    "command": { "kind": "Trigger", "execute": { "cmd": "true", "args": [] } }
+                },
+                "second": {
+                    "key": 7,
+                    "colour": "red",
+                    "command": {
+                        "kind": "Encoder",
+                        "execute": { "cmd": "true", "args": ["@V@"], "replace": "@V@", "map_min": 9 }
+                    }
+                },
+                "third": {
+                    "key": 8,
+                    "command": {
+                        "kind": "Encoder",
+                        "execute": { "cmd": "true", "args": [], "map_min": 9, "map_max": 1 }
+                    }
+                }
+            },
+            "passthrough": {
+                "output": "Thru",
+                "include": [{ "channels": { "min": 0, "max": 16 }, "keys": { "min": 0, "max": 200 } }]
+            }
+        }
+    ]
+}"#;
+    let diagnostics = validate::check_text(path, broken);
+    let found = errors(&diagnostics);
+    assert!(found
+        .iter()
+        .any(|message| message.starts_with("Detection threshold")));
+    assert!(found.contains(&String::from("Key 7 is already used by control `first`")));
+    assert!(found.contains(&String::from("`replace` is set but `map_max` is missing")));
+    assert!(found.contains(&String::from("`map_min` (9) is more than `map_max` (1)")));
+    // Channels are numbered from 1, keys go up to 127
+    assert!(found.contains(&String::from("Expected a whole number from 1 to 16")));
+    assert!(found.contains(&String::from("Expected a whole number from 0 to 127")));
+
+    let unknown = diagnostics
+        .iter()
+        .find(|diagnostic| diagnostic.message.starts_with("Unknown field `colour`"))
+        .unwrap();
+    assert_eq!(unknown.severity, validate::Severity::Warning);
+    assert_eq!((unknown.line, unknown.column), (18, 21));
+    // Known fields come from the types
+    assert!(unknown
+        .message
+        .ends_with("expected one of `key`, `command`, `threshold`"));
+
+    assert!(validate::find_executable("sh").is_some());
+    assert!(validate::find_executable("surely-not-a-command-here").is_none());
+}
+
+#[test]
+fn config_probe_devices() {
+    assert_eq!(util::levenshtein("kitten", "sitting"), 3);
+    assert!(util::matches_device("Arturia BeatStep", "arturia beatstep"));
+
+    let ports = [
+        types::PortInfo::parse(
+            "nanoKONTROL2:nanoKONTROL2 MIDI 1 20:0",
+            types::PortDirection::Input,
+        ),
+        types::PortInfo::parse(
+            "Arturia BeatStep:Arturia BeatStep MIDI 1 24:0",
+            types::PortDirection::Input,
+        ),
+    ];
+    let contents = r#"{
+    "log_level": "Warn",
+    "config": [
+        { "device": "Arturia Beatstep", "thresholds": {}, "controls": { "pad": { "key": 36 }, "knob": { "key": 7 } } },
+        { "device": "nanoKONTROL", "thresholds": {}, "controls": {} },
+        { "device": "nanoKONTROL2 MIDI 1", "thresholds": {}, "controls": {} },
+        { "device": "Launchpad", "thresholds": {}, "controls": {} },
+        { "device": "Software", "virtual_port": true, "thresholds": {}, "controls": {} }
+    ]
+}"#;
+    let document =
+        document::ConfigDocument::parse(std::path::Path::new("midiboard.json"), contents).unwrap();
+    let messages: Vec<(validate::Severity, String)> = validate::probe(&document, &ports)
+        .into_iter()
+        .map(|diagnostic| (diagnostic.severity, diagnostic.message))
+        .collect();
+
+    assert_eq!(messages.len(), 5);
+    assert_eq!(messages[0].0, validate::Severity::Info);
+    assert_eq!(
+        messages[1].1,
+        "Device `nanoKONTROL` was not found, did you mean 'nanoKONTROL2' (port 'nanoKONTROL2 MIDI 1')?"
+    );
+    assert!(messages[2].1.contains("did you mean 'nanoKONTROL2'"));
+    assert_eq!(
+        messages[3],
+        (
+            validate::Severity::Warning,
+            String::from("Device `Launchpad` was not found")
+        )
+    );
+    assert_eq!(messages[4].0, validate::Severity::Info);
+
+    let heard = std::collections::HashMap::from([(0, std::collections::HashSet::from([36]))]);
+    let unheard = validate::unheard_keys(&document, &heard);
+    assert_eq!(unheard.len(), 1);
+    assert_eq!(
+        unheard[0].message,
+        "Key 7 of control `knob` was not received while listening"
+    );
+
+    // Devices named by a reference are probed and listened to with the name filled in
+    std::env::set_var("MIDIBOARD_PROBE_TEST_DEVICE", "Arturia BeatStep");
+    let referenced = r#"{
+    "log_level": "Warn",
+    "config": [
+        { "device": "Software", "virtual_port": true, "thresholds": {}, "controls": {} },
+        { "device": "${MIDIBOARD_PROBE_TEST_DEVICE}", "thresholds": {}, "controls": {} }
+    ]
+}"#;
+    let document =
+        document::ConfigDocument::parse(std::path::Path::new("midiboard.json"), referenced)
+            .unwrap();
+    assert_eq!(
+        validate::connected_devices(&document, &ports),
+        [(1, String::from("Arturia BeatStep"))]
+    );
+    assert_eq!(
+        validate::probe(&document, &ports)[1].message,
+        "Device `Arturia BeatStep` is connected"
+    );
+}
+
+#[test]
+fn embedded_schema_matches_types() {
+    let asset = types::Asset::get("midiboard.schema.json").unwrap();
+    let embedded: serde_json::Value = serde_json::from_slice(&asset.data).unwrap();
+
+    // Regenerate it with `midiboard config schema > schema/midiboard.schema.json`
+    assert_eq!(
+        embedded,
+        crate::config::schema(),
+        "schema/midiboard.schema.json is out of date with the config types"
+    );
+
+    // Control names are restricted like `config validate` restricts them
+    let names = &embedded["definitions"]["Config"]["properties"]["controls"]["propertyNames"];
+    assert_eq!(names["pattern"], types::CONTROL_NAME_PATTERN);
+    assert!(document::is_valid_control_name("volume_2"));
+    assert!(!document::is_valid_control_name("Volume"));
+}
+
+#[test]
+fn config_formats_round_trip() {
+    let mut asset = types::Asset::get("midiboard.json").unwrap();
+    let skeleton = from_utf8(asset.data.to_mut().as_slice()).unwrap();
+    let value = util::parse_value(skeleton, types::ConfigFormat::Json).unwrap();
+
+    for format in [
+        types::ConfigFormat::Json,
+        types::ConfigFormat::Yaml,
+        types::ConfigFormat::Toml,
+        types::ConfigFormat::Ron,
+    ] {
+        let rendered = util::render_value(&value, format).unwrap();
+        assert_eq!(util::parse_value(&rendered, format).unwrap(), value);
+
+        let config = util::parse_config(&rendered, format).unwrap();
+        assert_eq!(config.config[0].controls["volume"].key(), 7);
+    }
+
+    assert_eq!(
+        "TOML".parse::<types::ConfigFormat>().unwrap(),
+        types::ConfigFormat::Toml
+    );
+
+    assert_eq!(
+        types::ConfigFormat::from_path(std::path::Path::new("/home/me/midiboard.yml")),
+        Some(types::ConfigFormat::Yaml)
+    );
+    assert_eq!(
+        types::ConfigFormat::from_path(std::path::Path::new("midiboard")),
+        None
+    );
+
+    // Edits on other formats are written back in the same format
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("midiboard.toml");
+    crate::config::generate_config(&config_path, types::ConfigFormat::Toml, true).unwrap();
+    let mut document =
+        document::ConfigDocument::load(&config_path, types::ConfigFormat::Toml).unwrap();
+    document
+        .set(
+            &document::parse_path("config[0].controls.volume.key").unwrap(),
+            &serde_json::json!(9),
+        )
+        .unwrap();
+    document.save().unwrap();
+
+    let written = std::fs::read_to_string(&config_path).unwrap();
+    assert!(written.contains("key = 9"));
+    let read_config = util::read_user_config(
+        Some(&String::from(config_path.as_os_str().to_str().unwrap())),
+        None,
+    )
+    .unwrap();
+    assert_eq!(read_config.config[0].controls["volume"].key(), 9);
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_search_path() {
+    let dir = tempdir().unwrap();
+    let home = dir.path().join("home");
+    let xdg = dir.path().join("xdg");
+    std::fs::create_dir_all(xdg.join("midiboard")).unwrap();
+    std::fs::create_dir_all(&home).unwrap();
+
+    let vars = |environment: Option<&'static str>| {
+        let home = home.clone();
+        let xdg = xdg.clone();
+        move |name: &str| match name {
+            "HOME" => Some(home.clone().into_os_string()),
+            "XDG_CONFIG_HOME" => Some(xdg.clone().into_os_string()),
+            "MIDIBOARD_CONFIG" => environment.map(std::ffi::OsString::from),
+            _ => None,
+        }
+    };
+
+    // Nothing exists yet, new files go to the home directory
+    let location = util::find_config_with(None, None, vars(None)).unwrap();
+    assert_eq!(location.source, types::ConfigSource::Default);
+    assert_eq!(location.path, home.join("midiboard.json"));
+    let location =
+        util::find_config_with(None, Some(&types::ConfigFormat::Yaml), vars(None)).unwrap();
+    assert_eq!(location.path, home.join("midiboard.yaml"));
+
+    write(home.join("midiboard.json"), "{}").unwrap();
+    let location = util::find_config_with(None, None, vars(None)).unwrap();
+    assert_eq!(location.source, types::ConfigSource::Search);
+    assert_eq!(location.path, home.join("midiboard.json"));
+
+    // The XDG directory comes before the home directory
+    write(xdg.join("midiboard").join("midiboard.toml"), "").unwrap();
+    let location = util::find_config_with(None, None, vars(None)).unwrap();
+    assert_eq!(location.path, xdg.join("midiboard").join("midiboard.toml"));
+    assert_eq!(location.format, types::ConfigFormat::Toml);
+
+    let location = util::find_config_with(None, None, vars(Some("/tmp/other.yml"))).unwrap();
+    assert_eq!(location.source, types::ConfigSource::Environment);
+    assert_eq!(location.format, types::ConfigFormat::Yaml);
+
+    let argument = String::from("./midiboard.ron");
+    let location =
+        util::find_config_with(Some(&argument), None, vars(Some("/tmp/other.yml"))).unwrap();
+    assert_eq!(location.source, types::ConfigSource::Argument);
+    assert_eq!(location.path, std::path::PathBuf::from("./midiboard.ron"));
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_includes() {
+    let dir = tempdir().unwrap();
+    let main = dir.path().join("midiboard.json");
+    let device = |name: &str| {
+        format!(
+            r#"{{ "device": "{}", "thresholds": {{ "encoder": {{ "activation": 300, "detection": 100 }}, "switch": {{ "activation": 400 }}, "trigger": {{ "activation": 1000 }} }}, "controls": {{}} }}"#,
+            name
+        )
+    };
+    std::fs::create_dir_all(dir.path().join("devices.d")).unwrap();
+    write(
+        &main,
+        format!(
+            r#"{{ "log_level": "Warn", "include": ["devices.d/*.json", "extra.yaml"], "config": [{}] }}"#,
+            device("Main")
+        ),
+    )
+    .unwrap();
+    write(
+        dir.path().join("devices.d").join("b.json"),
+        format!(r#"{{ "config": [{}] }}"#, device("Second")),
+    )
+    .unwrap();
+    write(
+        dir.path().join("devices.d").join("a.json"),
+        format!(r#"{{ "config": [{}] }}"#, device("First")),
+    )
+    .unwrap();
+    write(dir.path().join("extra.yaml"), "config: []\n").unwrap();
+
+    // Globs are expanded in order, after the devices of the main file
+    let data = util::read_user_config(Some(&main.display().to_string()), None).unwrap();
+    let names: Vec<&str> = data
+        .config
+        .iter()
+        .map(|entry| entry.device.as_str())
+        .collect();
+    assert_eq!(names, ["Main", "First", "Second"]);
+    assert!(validate::check_file(&main, types::ConfigFormat::Json)
+        .unwrap()
+        .is_empty());
+
+    // The same device twice is reported in the file that repeats it
+    write(
+        dir.path().join("devices.d").join("c.json"),
+        format!(r#"{{ "config": [{}] }}"#, device("first")),
+    )
+    .unwrap();
+    assert!(util::read_user_config(Some(&main.display().to_string()), None).is_err());
+    let diagnostics = validate::check_file(&main, types::ConfigFormat::Json).unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].file,
+        dir.path().join("devices.d").join("c.json")
+    );
+    assert_eq!(diagnostics[0].line, 1);
+
+    // Problems inside an included file point at that file
+    write(
+        dir.path().join("devices.d").join("c.json"),
+        r#"{ "config": [{ "device": "Third" }] }"#,
+    )
+    .unwrap();
+    let diagnostics = validate::check_file(&main, types::ConfigFormat::Json).unwrap();
+    assert!(diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.file == dir.path().join("devices.d").join("c.json")));
+    assert!(!diagnostics.is_empty());
+
+    // A file including itself would never end
+    write(
+        dir.path().join("devices.d").join("c.json"),
+        r#"{ "include": ["../midiboard.json"] }"#,
+    )
+    .unwrap();
+    assert!(preprocess::collect(&main, types::ConfigFormat::Json).is_err());
+    let diagnostics = validate::check_file(&main, types::ConfigFormat::Json).unwrap();
+    assert_eq!(
+        diagnostics[0].path,
+        document::parse_path("include").unwrap()
+    );
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_named_commands() {
+    let dir = tempdir().unwrap();
+    let main = dir.path().join("midiboard.json");
+    let config = |volume: &str, mute: &str| {
+        format!(
+            r#"{{
+    "log_level": "Warn",
+    "include": ["commands.json"],
+    "config": [
+        {{
+            "device": "Test",
+            "thresholds": {{
+                "encoder": {{ "activation": 300, "detection": 100 }},
+                "switch": {{ "activation": 400 }},
+                "trigger": {{ "activation": 1000 }}
+            }},
+            "controls": {{
+                "volume": {{ "key": 1, "command": {{ "kind": "Encoder", "execute": {} }} }},
+                "mute": {{ "key": 2, "command": {{ "kind": "Trigger", "execute": {} }} }}
+            }}
+        }}
+    ]
+}}"#,
+            volume, mute
+        )
+    };
+    write(
+        dir.path().join("commands.json"),
+        r#"{ "commands": { "set_volume": { "cmd": "pactl", "args": ["set-sink-volume", "{{sink}}", "@V@%"], "replace": "@V@", "map_min": 0, "map_max": 100 } } }"#,
+    )
+    .unwrap();
+    write(
+        &main,
+        config(
+            r#"{ "use": "set_volume", "with": { "sink": "@DEFAULT_SINK@" } }"#,
+            r#"{ "cmd": "pactl", "args": ["set-sink-mute", "@DEFAULT_SINK@", "toggle"] }"#,
+        ),
+    )
+    .unwrap();
+
+    let data = util::read_user_config(Some(&main.display().to_string()), None).unwrap();
+    let volume = data.config[0].controls.get("volume").unwrap();
+    match volume.command() {
+        types::Command::Encoder(encoder) => {
+            assert_eq!(encoder.execute.cmd, "pactl");
+            assert_eq!(
+                encoder.execute.args,
+                ["set-sink-volume", "@DEFAULT_SINK@", "@V@%"]
+            );
+            assert_eq!(encoder.execute.map_max, Some(100));
+        }
+        command => panic!("Unexpected command {:?}", command),
+    }
+    let errors = |diagnostics: Vec<validate::Diagnostic>| {
+        diagnostics
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == validate::Severity::Error)
+            .collect::<Vec<validate::Diagnostic>>()
+    };
+    assert!(errors(validate::check_file(&main, types::ConfigFormat::Json).unwrap()).is_empty());
+
+    // Unknown commands and parameters are errors where the reference is
+    write(
+        &main,
+        config(
+            r#"{ "use": "set_volume", "with": { "sink": "0", "card": "1" } }"#,
+            r#"{ "use": "mute" }"#,
+        ),
+    )
+    .unwrap();
+    assert!(util::read_user_config(Some(&main.display().to_string()), None).is_err());
+    let diagnostics = errors(validate::check_file(&main, types::ConfigFormat::Json).unwrap());
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.message.contains("no parameter `card`")));
+    assert!(diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.message.contains("no command named `mute`")));
+    assert_eq!(diagnostics[0].file, main);
+
+    write(
+        &main,
+        config(
+            r#"{ "use": "set_volume" }"#,
+            r#"{ "use": "set_volume", "with": { "sink": "1" } }"#,
+        ),
+    )
+    .unwrap();
+    let diagnostics = errors(validate::check_file(&main, types::ConfigFormat::Json).unwrap());
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("parameter `sink`"));
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn config_interpolation() {
+    let dir = tempdir().unwrap();
+    let token = dir.path().join("token");
+    write(&token, "secret\n").unwrap();
+    let var = |name: &str| match name {
+        "HOME" => Some(std::ffi::OsString::from("/home/user")),
+        "EMPTY" => Some(std::ffi::OsString::new()),
+        _ => None,
+    };
+    let text = |text: &str| preprocess::interpolate_text(text, &var);
+
+    assert_eq!(text("${HOME}/bin/obs").unwrap(), "/home/user/bin/obs");
+    assert_eq!(text("${env:HOME}").unwrap(), "/home/user");
+    assert_eq!(
+        text(&format!("--password=${{file:{}}}", token.display())).unwrap(),
+        "--password=secret"
+    );
+    assert_eq!(text("${SINK:-@DEFAULT_SINK@}").unwrap(), "@DEFAULT_SINK@");
+    assert_eq!(text("${EMPTY:-fallback}").unwrap(), "fallback");
+    assert_eq!(text("${file:/nonexistent:-none}").unwrap(), "none");
+    assert_eq!(text("cost: $${HOME} $5").unwrap(), "cost: ${HOME} $5");
+
+    assert!(text("${OBS_PASSWORD}")
+        .unwrap_err()
+        .to_string()
+        .contains("OBS_PASSWORD"));
+    assert!(text("${file:/nonexistent}").is_err());
+    assert!(text("${HOME").is_err());
+    assert!(text("${secret:HOME}").is_err());
+
+    // Only device names and commands are filled in, with the path of the failing value
+    let mut value = serde_json::json!({
+        "config": [{
+            "device": "${HOME}",
+            "controls": {
+                "mute": { "command": { "kind": "Trigger", "execute": { "cmd": "${HOME}/mute", "args": ["${HOME}"], "replace": "${HOME}" } } }
+            }
+        }]
+    });
+    preprocess::interpolate_with(&mut value, var).unwrap();
+    let execute = &value["config"][0]["controls"]["mute"]["command"]["execute"];
+    assert_eq!(value["config"][0]["device"], "/home/user");
+    assert_eq!(execute["cmd"], "/home/user/mute");
+    assert_eq!(execute["args"][0], "/home/user");
+    assert_eq!(execute["replace"], "${HOME}");
+
+    let mut value = serde_json::json!({ "config": [{ "device": "Test", "controls": { "obs": {
+        "command": { "kind": "Trigger", "execute": { "cmd": "obs-cli", "args": ["${OBS_PASSWORD}"] } }
+    } } }] });
+    let error = preprocess::interpolate_with(&mut value, var).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("config[0].controls.obs.command.execute.args[0]"));
+
+    dir.close().unwrap()
+}
+
+#[test]
+fn reload_keeps_unchanged_states() {
+    let mut asset = types::Asset::get("midiboard.json").unwrap();
+    let skeleton = from_utf8(asset.data.to_mut().as_slice()).unwrap();
+    let old = config_from_str(skeleton).config.remove(0);
+    let state = |control: &str| types::KeyState {
+        control: String::from(control),
+        detection_threshold: None,
+        activation_threshold: std::time::Duration::from_millis(400),
+        detections: vec![],
+        start: std::time::Instant::now(),
+        switch: Some(types::SwitchState::new(1, 2)),
+    };
+    let states =
+        std::collections::HashMap::from([(7, Some(state("volume"))), (45, Some(state("pause")))]);
+
+    assert!(run::diff_configs(std::slice::from_ref(&old), std::slice::from_ref(&old)).is_empty());
+
+    // Only the edited control loses its state
+    let mut new = old.clone();
+    let mut pause = new.controls.remove("pause").unwrap();
+    if let types::InputOption::Normal(input) = &mut pause {
+        input.key = 46;
+    }
+    new.controls.insert(String::from("pause"), pause);
+    assert_eq!(
+        run::diff_configs(std::slice::from_ref(&old), std::slice::from_ref(&new)),
+        [run::DeviceChange::Changed(new.clone())]
+    );
+    let carried = run::carried_states(&old, &states, &new);
+    assert_eq!(carried[&7].as_ref().unwrap().control, "volume");
+    assert_eq!(
+        carried[&7].as_ref().unwrap().switch,
+        Some(types::SwitchState::new(1, 2))
+    );
+    assert!(carried[&46].is_none());
+    assert!(!carried.contains_key(&45));
+
+    // Thresholds are part of the state, so changing them resets every control
+    new.thresholds.switch.activation = 500;
+    let carried = run::carried_states(&old, &states, &new);
+    assert!(carried.values().all(|state| state.is_none()));
+
+    let mut renamed = old.clone();
+    renamed.device = String::from("Other Device");
+    assert_eq!(
+        run::diff_configs(std::slice::from_ref(&old), std::slice::from_ref(&renamed)),
+        [
+            run::DeviceChange::Removed(old.device.clone()),
+            run::DeviceChange::Added(renamed)
+        ]
+    );
+}
+
+#[test]
+fn shutdown_stops_running_commands() {
+    let runtime = run::Runtime::default();
+    let log = util::Logger::new(types::LogLevel::Error);
+    let command = |cmd: &str, args: &[&str]| types::CommandData {
+        cmd: String::from(cmd),
+        args: args.iter().map(|arg| String::from(*arg)).collect(),
+        replace: None,
+        map_max: None,
+        map_min: None,
+        velocity: None,
+    };
+
+    // Finished commands are not waited for
+    let quick = command("true", &[]);
+    assert!(run::spawn_command(&String::from("quick"), &quick, &None, &runtime, log).is_ok());
+    assert!(run::wait_for_children(
+        &runtime,
+        std::time::Duration::ZERO,
+        log
+    ));
+
+    let slow = command("sleep", &["10"]);
+    let thread_runtime = runtime.clone();
+    let handle = std::thread::spawn(move || {
+        run::spawn_command(&String::from("slow"), &slow, &None, &thread_runtime, log)
+    });
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let started = std::time::Instant::now();
+    assert!(!run::wait_for_children(
+        &runtime,
+        std::time::Duration::from_millis(300),
+        log
+    ));
+    assert!(handle.join().unwrap().is_err());
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn control_socket_requests() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("midiboard.sock");
+    let log = util::Logger::new(types::LogLevel::Error);
+    let (sender, requests) = std::sync::mpsc::channel::<control::Pending>();
+    control::listen(&path, sender, log).unwrap();
+
+    // Stands in for the main thread of `run`
+    std::thread::spawn(move || {
+        for (request, reply) in requests {
+            let response = match request {
+                types::ControlRequest::Reload => {
+                    types::ControlResponse::failure(&anyhow::Error::msg("invalid config"))
+                }
+                request => {
+                    types::ControlResponse::success(Some(serde_json::to_value(&request).unwrap()))
+                }
+            };
+            reply.send(response).unwrap();
+        }
+    });
+
+    let set = types::ControlRequest::Set {
+        device: String::from("Midi Controller"),
+        control: String::from("mute"),
+        state: types::InitialSwitchState::ON,
+    };
+    let response = control::send(&path, &set).unwrap();
+    assert!(response.ok);
+    assert_eq!(
+        response.result.unwrap(),
+        serde_json::json!({
+            "command": "set",
+            "device": "Midi Controller",
+            "control": "mute",
+            "state": "ON"
+        })
+    );
+    let response = control::send(&path, &types::ControlRequest::Reload).unwrap();
+    assert_eq!(response.error.as_deref(), Some("invalid config"));
+
+    // A second service doesn't take over a socket that is in use
+    let (sender, _requests) = std::sync::mpsc::channel();
+    assert!(control::listen(&path, sender, log).is_err());
+
+    // Nor removes a file that isn't a socket
+    let file = dir.path().join("notes.txt");
+    write(&file, "keep me").unwrap();
+    let (sender, _requests) = std::sync::mpsc::channel();
+    let error = control::listen(&file, sender, log).unwrap_err();
+    assert!(error.to_string().contains("is not a socket"));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+}
+
+#[test]
+fn switch_states_are_restored() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("state").join("state.json");
+    let log = util::Logger::new(types::LogLevel::Error);
+
+    let state_file = state::StateFile::open(path.clone(), log);
+    state_file.save_switch("Midi Controller", "mute", types::InitialSwitchState::ON);
+    for value in 80..=87 {
+        state_file.save_encoder("Midi Controller", "volume", value);
+    }
+    // Changes are written in the background, or right away when flushing
+    state_file.flush().unwrap();
+    assert!(!path.with_extension("json.tmp").exists());
+
+    let saved = state::StateFile::open(path, log).device("Midi Controller");
+    assert_eq!(saved.switches["mute"], types::InitialSwitchState::ON);
+    assert_eq!(saved.encoders["volume"], 87);
+
+    let config = device_from(&device_config(
+        400,
+        r#"
+            "volume": {"key": 7, "command": {"kind": "Encoder", "execute": {"cmd": "true", "args": []}}},
+            "mute": {"key": 8, "command": {
+                "kind": "Switch", "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []},
+                "initial_state": "restore"
+            }},
+            "light": {"key": 9, "command": {
+                "kind": "Switch", "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []},
+                "initial_state": "OFF"
+            }}"#,
+    ));
+    let mut saved = saved;
+    saved
+        .switches
+        .insert(String::from("light"), types::InitialSwitchState::ON);
+
+    // Only switches set to restore take their saved state
+    let states = run::restored_states(&config, &saved);
+    assert_eq!(
+        run::switch_state(types::InitialSwitchState::Restore, states[&8].as_ref()),
+        types::InitialSwitchState::ON
+    );
+    assert!(states[&9].is_none());
+    assert_eq!(states[&7].as_ref().unwrap().detections, [87]);
+
+    // With nothing saved, restored switches start OFF
+    let states = run::restored_states(&config, &types::SavedDevice::default());
+    assert!(states[&8].is_none());
+    assert_eq!(
+        run::switch_state(types::InitialSwitchState::Restore, None),
+        types::InitialSwitchState::OFF
+    );
+}
+
+#[test]
+fn switch_state_machine() {
+    let switch = |activation: u64, initial_state: &str| {
+        TestDevice::new(&device_config(
+            activation,
+            &format!(
+                r#"
+            "mute": {{"key": 8, "command": {{
+                "kind": "Switch",
+                "on": {{"cmd": "true", "args": []}},
+                "off": {{"cmd": "true", "args": []}},
+                "initial_state": "{}"
+            }}}}"#,
+                initial_state
+            ),
+        ))
+    };
+    let on = Some(types::ActivationKind::Switch { on: true });
+    let off = Some(types::ActivationKind::Switch { on: false });
+
+    assert_eq!(types::SwitchState::new(5, 3).position, 2);
+    assert_eq!(types::SwitchState::new(2, 3).next().position, 0);
+    assert_eq!(types::SwitchState::new(0, 3).previous().position, 2);
+    assert_eq!(
+        types::SwitchState::from(types::InitialSwitchState::Restore),
+        types::SwitchState::new(0, 2)
+    );
+
+    // The first press already toggles, and every press after the threshold does again
+    let mut device = switch(0, "OFF");
+    assert_eq!(device.press(8, 127), on);
+    for _ in 0..100 {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        device.press(8, 127);
+    }
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    assert_eq!(device.press(8, 127), off);
+    let state = device.states[&8].as_ref().unwrap();
+    assert_eq!(state.switch, Some(types::SwitchState::new(0, 2)));
+    assert_eq!(state.detections, [127]);
+
+    // Presses too close to the last one are ignored
+    let mut device = switch(10_000, "ON");
+    assert_eq!(device.press(8, 127), off);
+    assert_eq!(device.press(8, 127), None);
+    assert_eq!(
+        run::switch_state(types::InitialSwitchState::ON, device.states[&8].as_ref()),
+        types::InitialSwitchState::OFF
+    );
+
+    // Keys without a control are not tracked
+    assert_eq!(device.press(9, 127), None);
+    assert!(!device.states.contains_key(&9));
+}
+
+#[test]
+fn cycle_steps_through_states() {
+    let contents = device_config(
+        0,
+        r#"
+            "output": {"key": 20, "command": {
+                "kind": "Cycle",
+                "initial_state": "headphones",
+                "back_key": 21,
+                "back_values": {"min": 0, "max": 63},
+                "states": [
+                    {"name": "speakers", "execute": {"cmd": "true", "args": []}, "feedback": 5},
+                    {"name": "headphones", "execute": {"cmd": "true", "args": []}},
+                    {"name": "hdmi", "execute": {"cmd": "true", "args": []}}
+                ]
+            }}"#,
+    );
+    let mut device = TestDevice::new(&contents);
+    let mut press = |key: u8, value: u8| {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        match device.press(key, value) {
+            Some(types::ActivationKind::Cycle { position }) => Some(position),
+            None => None,
+            _ => panic!("not a cycle activation"),
+        }
+    };
+
+    // Forwards from the initial state, wrapping around
+    assert_eq!(press(20, 127), Some(2));
+    assert_eq!(press(20, 127), Some(0));
+    // Backwards with the back key or a soft press
+    assert_eq!(press(21, 127), Some(2));
+    assert_eq!(press(20, 10), Some(1));
+    // A release is not a press, however long the key was held, even with a value that would
+    // step back
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    assert_eq!(press(20, 0), None);
+    assert_eq!(
+        device.states[&20].as_ref().unwrap().switch,
+        Some(types::SwitchState::new(1, 3))
+    );
+
+    let path = std::path::Path::new("midiboard.json");
+    let broken = contents
+        .replace(
+            r#""initial_state": "headphones""#,
+            r#""initial_state": "tv""#,
+        )
+        .replace(r#""back_key": 21"#, r#""back_key": 20"#)
+        .replace(r#""name": "hdmi""#, r#""name": "speakers""#);
+    let found: Vec<String> = validate::check_text(path, &broken)
+        .into_iter()
+        .filter(|diagnostic| diagnostic.severity == validate::Severity::Error)
+        .map(|diagnostic| diagnostic.message)
+        .collect();
+    assert!(found.contains(&String::from("There is no state named `tv`")));
+    assert!(found.contains(&String::from("There is already a state named `speakers`")));
+    assert!(found.contains(&String::from("Key 20 is already used by control `output`")));
+    assert!(validate::check_text(path, &contents)
+        .iter()
+        .all(|diagnostic| diagnostic.severity != validate::Severity::Error));
+}
+
+#[test]
+fn switch_groups_act_like_radio_buttons() {
+    let contents = device_config(
+        0,
+        r#"
+            "scene_a": {"key": 8, "command": {
+                "kind": "Switch", "group": "scene", "initial_state": "ON",
+                "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []}
+            }},
+            "scene_b": {"key": 9, "command": {
+                "kind": "Switch", "group": "scene", "initial_state": "OFF",
+                "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []}
+            }},
+            "scene_c": {"key": 10, "command": {
+                "kind": "Switch", "group": "scene", "initial_state": "OFF", "group_silent": true,
+                "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []}
+            }}"#,
+    );
+    let mut device = TestDevice::new(&contents);
+    // Whether the press turned its switch ON, then the switches it turned OFF with whether
+    // `off` runs
+    let mut press = |key: u8| {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let on = device.press(key, 127).is_some();
+        let released: Vec<(String, bool)> = run::release_group(
+            &mut device.states,
+            key,
+            &device.config,
+            &device.config.get_controls_by_key(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(event, activation)| (event.state.control, activation.valid))
+        .collect();
+        (on, released)
+    };
+
+    assert_eq!(press(9), (true, vec![(String::from("scene_a"), true)]));
+    // Pressing the switch that is ON keeps it ON
+    assert_eq!(press(9), (false, vec![]));
+    assert_eq!(press(10), (true, vec![(String::from("scene_b"), true)]));
+    assert_eq!(press(8), (true, vec![(String::from("scene_c"), false)]));
+
+    let path = std::path::Path::new("midiboard.json");
+    let found: Vec<String> = validate::check_text(
+        path,
+        &contents.replace(
+            r#""initial_state": "OFF", "group_silent""#,
+            r#""initial_state": "ON", "group_silent""#,
+        ),
+    )
+    .into_iter()
+    .map(|diagnostic| diagnostic.message)
+    .collect();
+    assert!(found.contains(&String::from(
+        "Only one switch of group `scene` can be ON, `scene_a` starts ON too"
+    )));
+}
+
+#[test]
+fn momentary_switch_follows_presses_and_releases() {
+    let contents = device_config(
+        10_000,
+        r#"
+            "talk": {"key": 30, "command": {
+                "kind": "Switch", "momentary": true, "initial_state": "OFF",
+                "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []}
+            }}"#,
+    );
+    let mut device = TestDevice::new(&contents);
+    let on = Some(types::ActivationKind::Switch { on: true });
+    let off = Some(types::ActivationKind::Switch { on: false });
+
+    // The release comes right away, the switch threshold doesn't hold it back
+    assert_eq!(device.press(30, 100), on);
+    assert_eq!(device.press(30, 90), None);
+    assert_eq!(device.press(30, 0), off);
+    assert_eq!(device.press(30, 0), None);
+    assert_eq!(device.press(30, 127), on);
+
+    let path = std::path::Path::new("midiboard.json");
+    let found: Vec<String> = validate::check_text(
+        path,
+        &contents.replace(r#""initial_state": "OFF""#, r#""initial_state": "ON""#),
+    )
+    .into_iter()
+    .map(|diagnostic| diagnostic.message)
+    .collect();
+    assert!(found.contains(&String::from(
+        "A momentary switch is only ON while held, it should start OFF"
+    )));
+}
+
+#[test]
+fn commands_of_a_device_run_in_order() {
+    let log = util::Logger::new(types::LogLevel::Error);
+    let dir = tempdir().unwrap();
+    let ran = dir.path().join("ran");
+    let mut device = TestDevice::new(
+        &device_config(
+            10_000,
+            r#"
+            "talk": {"key": 30, "command": {
+                "kind": "Switch", "momentary": true, "initial_state": "OFF",
+                "on": {"cmd": "sh", "args": ["-c", "sleep 0.2; echo on >> RAN"]},
+                "off": {"cmd": "sh", "args": ["-c", "echo off >> RAN"]}
+            }}"#,
+        )
+        .replace("RAN", ran.to_str().unwrap()),
+    );
+    let (jobs, worker) = run::start_worker(
+        std::sync::Arc::new(device.config.clone()),
+        run::Runtime::default(),
+        log,
+    );
+
+    // A tap runs `on` and then `off`, even when `on` takes longer
+    for value in [100, 0] {
+        let (event, activation) = device.process(30, value).unwrap();
+        jobs.send(run::Job::Key {
+            released: vec![],
+            event,
+            activation,
+        })
+        .unwrap();
+    }
+    drop(jobs);
+    worker.join().unwrap();
+    assert_eq!(std::fs::read_to_string(&ran).unwrap(), "on\noff\n");
+}
+
+#[test]
+fn toggle_switch_ignores_releases() {
+    let mut device = TestDevice::new(&device_config(
+        20,
+        r#"
+            "mute": {"key": 30, "command": {
+                "kind": "Switch", "initial_state": "OFF",
+                "on": {"cmd": "true", "args": []}, "off": {"cmd": "true", "args": []}
+            }}"#,
+    ));
+    let mut send = |message: &[u8]| {
+        let (key, value) = types::MidiMessage::parse(message)
+            .and_then(|message| message.key_value())
+            .unwrap();
+        device.press(key, value)
+    };
+
+    // A long press toggles once, the Note Off after it is not another press whatever its
+    // velocity, and neither is a Note On of 0
+    assert_eq!(
+        send(&[0x90, 30, 100]),
+        Some(types::ActivationKind::Switch { on: true })
+    );
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(send(&[0x80, 30, 64]), None);
+    assert_eq!(send(&[0x90, 30, 0]), None);
+    assert_eq!(send(&[0xB0, 30, 0]), None);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(
+        send(&[0x90, 30, 100]),
+        Some(types::ActivationKind::Switch { on: false })
+    );
+}
+
+#[test]
+fn trigger_velocity_zones() {
+    let log = util::Logger::new(types::LogLevel::Error);
+    let contents = device_config(
+        0,
+        r#"
+            "snare": {"key": 38, "command": {
+                "kind": "Trigger",
+                "zones": [
+                    {"values": {"min": 1, "max": 40}, "execute": {"cmd": "soft", "args": []}},
+                    {"values": {"min": 41, "max": 100}, "execute": {"cmd": "medium", "args": []}},
+                    {"values": {"min": 101, "max": 127}, "execute": {"cmd": "hard", "args": []}}
+                ]
+            }}"#,
+    );
+    let mut device = TestDevice::new(&contents);
+    let trigger = match device
+        .config
+        .get_control(&String::from("snare"))
+        .unwrap()
+        .command()
+    {
+        types::Command::Trigger(trigger) => trigger,
+        _ => panic!("not a trigger"),
+    };
+    let zone = |velocity: u8| trigger.command(velocity).map(|data| data.cmd.clone());
+    assert_eq!(zone(40), Some(String::from("soft")));
+    assert_eq!(zone(41), Some(String::from("medium")));
+    assert_eq!(zone(127), Some(String::from("hard")));
+    assert_eq!(zone(0), None);
+
+    // The hit fires it with its velocity, the release after it doesn't
+    let mut hit = |velocity: u8| {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        device
+            .process(38, velocity)
+            .map(|(event, _)| *event.state.detections.last().unwrap())
+    };
+    assert_eq!(hit(90), Some(90));
+    assert_eq!(hit(0), None);
+    assert_eq!(hit(30), Some(30));
+
+    // The raw velocity replaces its placeholder
+    let runtime = run::Runtime::default();
+    let data = types::CommandData {
+        cmd: String::from("test"),
+        args: ["@VELOCITY@", "-eq", "90"].map(String::from).to_vec(),
+        replace: None,
+        map_max: None,
+        map_min: None,
+        velocity: Some(String::from("@VELOCITY@")),
+    };
+    let control = String::from("snare");
+    assert!(run::spawn_command(&control, &data, &Some(&90), &runtime, log).is_ok());
+    assert!(run::spawn_command(&control, &data, &Some(&91), &runtime, log).is_err());
+
+    let path = std::path::Path::new("midiboard.json");
+    let found: Vec<String> =
+        validate::check_text(path, &contents.replace(r#""min": 41"#, r#""min": 30"#))
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+    assert!(found.contains(&String::from(
+        "Overlaps zone 0, which is executed for the velocities in both"
+    )));
+}
+
+#[test]
+fn pressure_drives_encoders_at_a_limited_rate() {
+    let contents = device_config(
+        0,
+        r#"
+            "pad": {"key": 36, "command": {
+                "kind": "Trigger", "execute": {"cmd": "true", "args": []}
+            }},
+            "pad_pressure": {"key": 36, "threshold": {"activation": 100}, "command": {
+                "kind": "Encoder", "source": "PolyAftertouch",
+                "execute": {"cmd": "test", "args": ["@P@", "-le", "100"], "replace": "@P@", "map_min": 0, "map_max": 100}
+            }},
+            "channel_pressure": {"key": 0, "command": {
+                "kind": "Encoder", "source": "ChannelPressure",
+                "execute": {"cmd": "true", "args": []}
+            }}"#,
+    );
+    let config = device_from(&contents);
+
+    // The pressure of a pad doesn't take the key of its hits
+    let controls = config.get_controls_by_key();
+    assert_eq!(controls.len(), 1);
+    assert_eq!(controls[&36], "pad");
+    let pressure =
+        |message: &[u8]| config.get_pressure_controls(&types::MidiMessage::parse(message).unwrap());
+    assert_eq!(pressure(&[0xA0, 36, 50]), ["pad_pressure"]);
+    assert!(pressure(&[0xA0, 37, 50]).is_empty());
+    assert_eq!(pressure(&[0xD3, 70]), ["channel_pressure"]);
+    assert!(pressure(&[0x90, 36, 50]).is_empty());
+
+    let mut pressures = run::Pressures::new();
+    let control = String::from("pad_pressure");
+    let start = std::time::Instant::now();
+    let at = |ms: u64| start + std::time::Duration::from_millis(ms);
+    let mut press = |value: u8, ms: u64| {
+        run::on_pressure(&mut pressures, &control, value, &config, at(ms)).unwrap()
+    };
+    assert_eq!(press(50, 0), Some(50));
+    // Within the activation threshold the latest value is held back until it's over
+    assert_eq!(press(70, 10), None);
+    assert_eq!(press(90, 20), None);
+    assert!(run::send_held(&mut pressures, at(99)).is_empty());
+    assert_eq!(
+        run::send_held(&mut pressures, at(100)),
+        [(control.clone(), 90)]
+    );
+    assert!(run::send_held(&mut pressures, at(1000)).is_empty());
+    // The release always gets through, and only once, and a value held before it is dropped
+    let mut press = |value: u8, ms: u64| {
+        run::on_pressure(&mut pressures, &control, value, &config, at(ms)).unwrap()
+    };
+    assert_eq!(press(100, 250), Some(100));
+    assert_eq!(press(110, 260), None);
+    assert_eq!(press(0, 270), Some(0));
+    assert_eq!(press(0, 280), None);
+    assert!(run::send_held(&mut pressures, at(1000)).is_empty());
+
+    let path = std::path::Path::new("midiboard.json");
+    assert!(validate::check_text(path, &contents)
+        .iter()
+        .all(|diagnostic| diagnostic.severity != validate::Severity::Error));
+}
+
+#[test]
+fn device_banks_take_turns() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("midiboard.json");
+    let contents = device_config(
+        0,
+        r#"
+            "volume": {"key": 7, "command": {"kind": "Encoder", "execute": {"cmd": "true", "args": []}}}"#,
+    )
+    .replace(
+        r#""log_level": "Error","#,
+        r#""log_level": "Error",
+    "commands": {"say": {"cmd": "echo", "args": ["{{word}}"]}},"#,
+    )
+    .replace(
+        r#""controls": {"#,
+        r#""initial_bank": "scenes",
+        "banks": {
+            "scenes": {
+                "scene": {"key": 8, "command": {"kind": "Trigger", "execute": {"use": "say", "with": {"word": "scene"}}}}
+            },
+            "effects": {
+                "delay": {"key": 8, "command": {"kind": "Trigger", "execute": {"cmd": "true", "args": []}}}
+            }
+        },
+        "controls": {"#,
+    );
+    write(&path, &contents).unwrap();
+    let config = util::read_user_config(Some(&path.display().to_string()), None)
+        .unwrap()
+        .config
+        .remove(0);
+
+    // The same key runs the control of the active bank, besides the controls outside banks
+    let keys = |bank: Option<&str>| config.with_bank(bank).get_controls_by_key();
+    assert_eq!(keys(None).len(), 1);
+    assert_eq!(keys(Some("scenes"))[&8], "scene");
+    assert_eq!(keys(Some("effects"))[&8], "delay");
+    assert_eq!(keys(Some("effects"))[&7], "volume");
+    assert_eq!(config.initial_bank.as_deref(), Some("scenes"));
+    match config.with_bank(Some("scenes")).controls["scene"].command() {
+        types::Command::Trigger(trigger) => {
+            assert_eq!(trigger.execute.unwrap().args, ["scene"])
+        }
+        command => panic!("Unexpected command {:?}", command),
+    }
+    assert!(validate::check_text(&path, &contents)
+        .iter()
+        .all(|diagnostic| diagnostic.severity != validate::Severity::Error));
+
+    // A bank can't take the keys or names of the controls outside banks
+    let broken = contents
+        .replace(r#""delay": {"key": 8"#, r#""volume": {"key": 7"#)
+        .replace(r#""initial_bank": "scenes""#, r#""initial_bank": "lights""#);
+    let found: Vec<String> = validate::check_text(&path, &broken)
+        .into_iter()
+        .map(|diagnostic| diagnostic.message)
+        .collect();
+    assert!(found.contains(&String::from("Key 7 is already used by control `volume`")));
+    assert!(found.contains(&String::from(
+        "There is already a control named `volume` in `controls`"
+    )));
+    assert!(found.contains(&String::from("There is no bank named `lights`")));
+
+    let request = types::ControlRequest::Bank {
+        device: String::from("Midi Controller"),
+        bank: String::from("effects"),
+    };
+    assert_eq!(
+        serde_json::to_value(&request).unwrap(),
+        serde_json::json!({"command": "bank", "device": "Midi Controller", "bank": "effects"})
+    );
+}
+
+#[test]
+fn config_options_go_before_subcommands() {
+    let parse = |args: &str| cli().try_get_matches_from(args.split_whitespace());
+
+    let matches = parse("midiboard config -p midiboard.yaml -f yaml remove device Keys").unwrap();
+    let (_, config_m) = matches.subcommand().unwrap();
+    let (name, remove_m) = config_m.subcommand().unwrap();
+    assert_eq!(name, "remove");
+    assert_eq!(
+        remove_m.get_one::<String>("path").map(String::as_str),
+        Some("midiboard.yaml")
+    );
+    assert!(parse("midiboard config -p midiboard.json -v").is_ok());
+}
+
+#[test]
+fn config_remove_takes_options_anywhere() {
+    let remove = |args: &str| {
+        let matches = cli().try_get_matches_from(args.split_whitespace()).unwrap();
+        let (_, config_m) = matches.subcommand().unwrap();
+        let (_, remove_m) = config_m.subcommand().unwrap();
+        remove_m.clone()
+    };
+
+    let remove_m = remove("midiboard config remove -p midiboard.json control volume --device Keys");
+    let (name, control_m) = remove_m.subcommand().unwrap();
+    assert_eq!(name, "control");
+    assert_eq!(
+        control_m.get_one::<String>("name").map(String::as_str),
+        Some("volume")
+    );
+    assert_eq!(
+        control_m.get_one::<String>("path").map(String::as_str),
+        Some("midiboard.json")
+    );
+
+    let remove_m = remove("midiboard config remove -p midiboard.json config[0].controls.volume");
+    assert!(remove_m.subcommand().is_none());
+    assert_eq!(
+        remove_m.get_one::<String>("key").map(String::as_str),
+        Some("config[0].controls.volume")
+    );
+}
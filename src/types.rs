@@ -1,8 +1,14 @@
 use anyhow::Error;
 use rust_embed::RustEmbed;
-use serde::Deserialize;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject, StringValidation};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
     time::{Duration, Instant},
 };
 
@@ -10,58 +16,403 @@ use std::{
 #[folder = "schema/"]
 pub struct Asset;
 
-#[derive(Debug, Deserialize)]
+/// Configuration file for the midiboard MIDI control CLI tool
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields, title = "Midiboard Config")]
 pub struct ConfigFile {
+    /// Validation schema for this file.
+    // Only meant for editors, it's never read
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub schema: Option<String>,
+    /// Other files with more devices, relative to this one. Globs like `devices.d/*.json` are accepted.
+    // Already resolved when the file is read
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Named commands that controls can reuse with `{"use": "name", "with": {...}}`. Every `{{parameter}}` in them is replaced with the value given in `with`.
+    // Already expanded into the controls when the file is read
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub commands: HashMap<String, CommandData>,
+    /// The actual configuration data. It's an array containing multiple possible devices and their controls.
+    #[serde(default)]
     pub config: Vec<Config>,
+    /// Which levels of logs are printed.
     pub log_level: LogLevel,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Config {
+    /// The exact name of the MIDI device to listen, as the system reads it. `${NAME}` and `${env:NAME}` are filled in when reading the file.
     pub device: String,
+    /// Actual control entries to listen for. List a unique, memorable name per entry. Only lowercase, numbers and low dash allowed.
+    #[schemars(schema_with = "control_list_schema")]
     pub controls: ControlList,
+    /// Time thresholds for each kind of control.
     pub thresholds: Thresholds,
+    /// Create a virtual input port named after `device` instead of connecting to a physical one, so other software can send events to it.
+    #[serde(default)]
+    pub virtual_port: bool,
+    /// Forwards incoming messages to another port, so other software can still use the device.
+    pub passthrough: Option<Passthrough>,
+    /// Command to be executed when midiboard stops, after the device is closed.
+    #[schemars(with = "Option<CommandDefinition>")]
+    pub on_shutdown: Option<CommandData>,
+    /// Output port to send LED feedback to, like the colours of `Cycle` states. The output port named like `device` by default.
+    pub feedback_output: Option<String>,
+    /// Sets of controls that take turns, by name. Only the controls of the active bank are listened to besides `controls`, so the same keys can do something else in each bank. `midiboard ctl bank` switches between them.
+    #[serde(default)]
+    #[schemars(schema_with = "bank_list_schema")]
+    pub banks: BankList,
+    /// Bank active when the device connects. None is, until one is switched to, if not set.
+    pub initial_bank: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct Passthrough {
+    /// Name of the output port to forward messages to, or of the virtual port to create.
+    pub output: String,
+    /// Create a virtual output port named after `output` instead of connecting to an existing one.
+    #[serde(default)]
+    pub virtual_port: bool,
+    /// Whether messages consumed by a configured control are forwarded too.
+    #[serde(default)]
+    pub forward: ForwardMode,
+    /// If not empty, only messages matching at least one of these filters are forwarded.
+    #[serde(default)]
+    pub include: Vec<MessageFilter>,
+    /// Messages matching any of these filters are never forwarded.
+    #[serde(default)]
+    pub exclude: Vec<MessageFilter>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardMode {
+    /// Forward everything except messages consumed by a configured control
+    #[default]
+    Unconsumed,
+    All,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct MessageFilter {
+    /// Message types matched by this filter.
+    pub types: Option<Vec<MessageType>>,
+    /// Inclusive range of channels (1 to 16) matched by this filter.
+    pub channels: Option<ValueRange>,
+    /// Inclusive range of note or controller numbers matched by this filter.
+    pub keys: Option<ValueRange>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct ValueRange {
+    #[schemars(range(max = 127))]
+    pub min: u8,
+    #[schemars(range(max = 127))]
+    pub max: u8,
+}
+
+impl ValueRange {
+    pub fn contains(&self, value: u8) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    // A channel or range of channels, like `--channel`. Channels go from 1 to 16, as in filters.
+    pub fn channels(range: &str) -> Result<Self, Error> {
+        let range = ValueRange::from_str(range)?;
+        if range.min < 1 || range.max > 16 {
+            return Err(Error::msg(format!(
+                "Channels go from 1 to 16, not {}-{}",
+                range.min, range.max
+            )));
+        }
+        Ok(range)
+    }
+}
+
+impl MessageFilter {
+    pub fn matches(&self, message: &MidiMessage) -> bool {
+        if let Some(types) = &self.types {
+            if !types.contains(&message.kind) {
+                return false;
+            }
+        }
+        if let Some(channels) = &self.channels {
+            match message.channel {
+                Some(channel) if channels.contains(channel) => {}
+                _ => return false,
+            }
+        }
+        if let Some(keys) = &self.keys {
+            match message.number {
+                Some(number) if keys.contains(number) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Passthrough {
+    pub fn should_forward(&self, message: &MidiMessage, consumed: bool) -> bool {
+        if consumed && self.forward == ForwardMode::Unconsumed {
+            return false;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|f| f.matches(message)) {
+            return false;
+        }
+        !self.exclude.iter().any(|f| f.matches(message))
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    NoteOff,
+    NoteOn,
+    PolyAftertouch,
+    ControlChange,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    System,
+}
+
+impl FromStr for MessageType {
+    type Err = Error;
+
+    // Accepts the variant names in any case, with or without dashes and low dashes (`note-on`, `NoteOn`...)
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().replace(['-', '_'], "").as_str() {
+            "noteoff" => Ok(MessageType::NoteOff),
+            "noteon" => Ok(MessageType::NoteOn),
+            "polyaftertouch" => Ok(MessageType::PolyAftertouch),
+            "controlchange" | "cc" => Ok(MessageType::ControlChange),
+            "programchange" => Ok(MessageType::ProgramChange),
+            "channelpressure" => Ok(MessageType::ChannelPressure),
+            "pitchbend" => Ok(MessageType::PitchBend),
+            "system" => Ok(MessageType::System),
+            _ => Err(Error::msg(format!("Unknown MIDI message type {}", name))),
+        }
+    }
+}
+
+impl FromStr for ValueRange {
+    type Err = Error;
+
+    // Either a single value (`10`) or an inclusive range (`1-9`)
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let (min, max) = range.split_once('-').unwrap_or((range, range));
+        let range = ValueRange {
+            min: min.trim().parse()?,
+            max: max.trim().parse()?,
+        };
+        if range.min > range.max {
+            return Err(Error::msg(format!(
+                "Range {}-{} starts after it ends",
+                range.min, range.max
+            )));
+        }
+        Ok(range)
+    }
+}
+
+// Decoded MIDI 1.0 message. Channels are numbered 1 to 16, as most devices label them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiMessage {
+    pub kind: MessageType,
+    pub channel: Option<u8>,
+    // Note, controller or key number, for the messages that carry one
+    pub number: Option<u8>,
+    // Velocity, controller value, pressure or program. Pitch bend only reports its 7 most significant bits.
+    pub value: Option<u8>,
+}
+
+impl MidiMessage {
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let status = *message.first()?;
+        if status < 0x80 {
+            // Running status is not supported, a status byte must always be present
+            return None;
+        }
+        if status >= 0xF0 {
+            return Some(MidiMessage {
+                kind: MessageType::System,
+                channel: None,
+                number: None,
+                value: None,
+            });
+        }
+
+        let channel = Some((status & 0x0F) + 1);
+        let first = message.get(1).copied();
+        let second = message.get(2).copied();
+
+        let (kind, number, value) = match status & 0xF0 {
+            0x80 => (MessageType::NoteOff, first, second),
+            // A Note On with velocity 0 is a Note Off by the spec
+            0x90 if second == Some(0) => (MessageType::NoteOff, first, second),
+            0x90 => (MessageType::NoteOn, first, second),
+            0xA0 => (MessageType::PolyAftertouch, first, second),
+            0xB0 => (MessageType::ControlChange, first, second),
+            0xC0 => (MessageType::ProgramChange, None, first),
+            0xD0 => (MessageType::ChannelPressure, None, first),
+            _ => (MessageType::PitchBend, None, second),
+        };
+
+        Some(MidiMessage {
+            kind,
+            channel,
+            number,
+            value,
+        })
+    }
+
+    // The key a message presses and with what value. A Note Off is a release, whatever velocity
+    // it comes with, and pressure is not a press of its note.
+    pub fn key_value(&self) -> Option<(u8, u8)> {
+        match (self.kind, self.number, self.value) {
+            (MessageType::PolyAftertouch, _, _) => None,
+            (MessageType::NoteOff, Some(key), Some(_)) => Some((key, 0)),
+            (_, Some(key), Some(value)) => Some((key, value)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Thresholds {
+    /// Time thresholds for activation of encoder controls.
     pub encoder: FullTimeThreshold,
+    /// Time thresholds for activation of switch controls.
     pub switch: TimeThreshold,
+    /// Time thresholds for activation of trigger controls.
     pub trigger: TimeThreshold,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
 #[serde(untagged)]
 pub enum Threshold {
     Full(FullTimeThreshold),
     Base(TimeThreshold),
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct FullTimeThreshold {
+    /// Total time in milliseconds since the first detection of the key that is sampled before executing the command.
     pub activation: u64,
+    /// Minimum time in milliseconds between two detections of the key for them to be counted separately.
     pub detection: u64,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct TimeThreshold {
+    /// Time in milliseconds after an activation during which new detections of the key don't execute the command again.
     pub activation: u64,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Input {
+    /// Key of the selected MIDI controller to listen for.
+    #[schemars(range(max = 127))]
     pub key: u8,
     pub command: Command,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct InputOverride {
+    /// Key of the selected MIDI controller to listen for.
+    #[schemars(range(max = 127))]
     pub key: u8,
+    /// Time thresholds for this control, replacing the ones of the device.
     pub threshold: Threshold,
     pub command: Command,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+// Formats the config file can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+    Ron,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            "ron" => Some(ConfigFormat::Ron),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Ron => "ron",
+        }
+    }
+
+    pub fn file_format(&self) -> config::FileFormat {
+        match self {
+            ConfigFormat::Json => config::FileFormat::Json,
+            ConfigFormat::Yaml => config::FileFormat::Yaml,
+            ConfigFormat::Toml => config::FileFormat::Toml,
+            ConfigFormat::Ron => config::FileFormat::Ron,
+        }
+    }
+}
+
+// Why a config file was picked, in order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Argument,
+    Environment,
+    Search,
+    Default,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLocation {
+    pub path: PathBuf,
+    pub format: ConfigFormat,
+    pub source: ConfigSource,
+    // Every file looked for in the search path, in order
+    pub searched: Vec<PathBuf>,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            "ron" => Ok(ConfigFormat::Ron),
+            _ => Err(Error::msg(format!(
+                "Unknown config format {}, expected json, yaml, toml or ron",
+                name
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, PartialOrd)]
 pub enum LogLevel {
     Error,
     Warn,
@@ -70,13 +421,16 @@ pub enum LogLevel {
     Trace,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
 pub enum InitialSwitchState {
     ON,
     OFF,
+    /// The state the switch was left in the last time, kept in the state file. OFF the first time.
+    #[serde(rename = "restore")]
+    Restore,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum InputOption {
     Overrode(InputOverride),
@@ -104,40 +458,187 @@ impl InputOption {
             Self::Normal(_) => None,
         }
     }
+
+    // Pressure the control reads, if it doesn't listen to its key
+    pub fn pressure(&self) -> Option<PressureSource> {
+        match self.command() {
+            Command::Encoder(data) => data.source,
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
 #[serde(tag = "kind")]
 pub enum Command {
+    /// Encoder reads increments or decrements, sampling several inputs and executing the command with the mapped value.
     Encoder(Encoder),
+    /// Switch starts with the given state and toggles it on every press, executing the corresponding command immediately.
     Switch(Switch),
+    /// Trigger simply executes the command immediately.
     Trigger(Trigger),
+    /// Cycle steps through an ordered list of states on every press, executing the command of the state it reaches.
+    Cycle(Cycle),
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Encoder {
+    /// Command to be executed when the encoder value changes.
+    #[schemars(with = "CommandDefinition")]
     pub execute: CommandData,
+    /// Pressure to read the value from instead of the key. The command runs at most once per activation threshold.
+    pub source: Option<PressureSource>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum PressureSource {
+    /// Pressure on the note of the key, from Poly Aftertouch messages.
+    PolyAftertouch,
+    /// Pressure on any note of the device, from Channel Pressure messages. The key is not used.
+    ChannelPressure,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Switch {
+    /// Command to be executed after triggering an ON event.
+    #[schemars(with = "CommandDefinition")]
     pub on: CommandData,
+    /// Command to be executed after triggering an OFF event.
+    #[schemars(with = "CommandDefinition")]
     pub off: CommandData,
+    /// Default state to be assumed at the start of the application, or `restore` to start in the last known state.
     pub initial_state: InitialSwitchState,
+    /// Name of a group of switches of which only one can be ON. Turning one ON turns the others OFF.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Whether turning this switch OFF because another one of its group turned ON only changes its state, without executing `off`.
+    #[serde(default)]
+    pub group_silent: bool,
+    /// Whether the switch is ON only while held, executing `on` when pressed and `off` when released, without debouncing.
+    #[serde(default)]
+    pub momentary: bool,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct Trigger {
+    /// Command to be executed after triggering the event, when no zone matches its velocity.
+    #[schemars(with = "Option<CommandDefinition>")]
+    pub execute: Option<CommandData>,
+    /// Velocity zones with a command each. The first zone the velocity falls in is executed.
+    #[serde(default)]
+    pub zones: Vec<VelocityZone>,
+}
+
+impl Trigger {
+    // The command for a hit of that velocity, if any
+    pub fn command(&self, velocity: u8) -> Option<&CommandData> {
+        self.zones
+            .iter()
+            .find(|zone| zone.values.contains(velocity))
+            .map(|zone| &zone.execute)
+            .or(self.execute.as_ref())
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct VelocityZone {
+    /// Velocities of the zone, both ends included.
+    pub values: ValueRange,
+    /// Command to be executed for a hit in this zone.
+    #[schemars(with = "CommandDefinition")]
     pub execute: CommandData,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct Cycle {
+    /// States to step through, in order. The first one comes again after the last one.
+    #[schemars(length(min = 1))]
+    pub states: Vec<CycleState>,
+    /// Name of the state to start in. The first one by default.
+    #[serde(default)]
+    pub initial_state: Option<String>,
+    /// Another key that steps backwards through the states, like a second pad.
+    #[serde(default)]
+    #[schemars(range(max = 127))]
+    pub back_key: Option<u8>,
+    /// Values of the control's own key that step backwards instead of forwards, like soft hits or the lower half of a rocker.
+    #[serde(default)]
+    pub back_values: Option<ValueRange>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct CycleState {
+    /// Name of the state, unique within the cycle.
+    pub name: String,
+    /// Command to be executed when the cycle reaches this state.
+    #[schemars(with = "CommandDefinition")]
+    pub execute: CommandData,
+    /// Velocity sent back to the key when the cycle reaches this state. Most controllers light the pad in a colour picked by it.
+    #[serde(default)]
+    #[schemars(range(max = 127))]
+    pub feedback: Option<u8>,
+}
+
+impl Cycle {
+    pub fn initial(&self) -> SwitchState {
+        let position = self
+            .initial_state
+            .as_ref()
+            .and_then(|name| self.states.iter().position(|state| &state.name == name))
+            .unwrap_or(0);
+        SwitchState::new(position, self.states.len())
+    }
+
+    pub fn steps_back(&self, value: u8) -> bool {
+        self.back_values
+            .is_some_and(|back_values| back_values.contains(value))
+    }
+}
+
+// What the file may hold where a command goes. References are expanded before the file is
+// deserialized, so this only exists to describe them in the schema.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum CommandDefinition {
+    Data(CommandData),
+    Use(CommandUse),
+}
+
+/// Reference to one of the named `commands`.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct CommandUse {
+    /// Name of the command to use.
+    #[serde(rename = "use")]
+    name: String,
+    /// Values of the command parameters, by name.
+    #[serde(default)]
+    with: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
 pub struct CommandData {
+    /// Base command to invoke. `${NAME}`, `${env:NAME}` and `${file:PATH}` are filled in when reading the file.
     pub cmd: String,
+    /// Args provided to the command. `${NAME}`, `${env:NAME}` and `${file:PATH}` are filled in when reading the file.
     pub args: Vec<String>,
+    /// String to replace with the mapped value, either in `cmd` or `args`.
     pub replace: Option<String>,
+    /// Integer to map to level 127 of the selected key.
     pub map_max: Option<i32>,
+    /// Integer to map to level 0 of the selected key.
     pub map_min: Option<i32>,
+    /// String to replace with the raw value of the key, from 0 to 127, either in `cmd` or `args`.
+    pub velocity: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -145,6 +646,7 @@ pub enum CommandKind {
     Encoder,
     Switch,
     Trigger,
+    Cycle,
 }
 
 #[derive(Debug, Clone)]
@@ -153,6 +655,8 @@ pub struct KeyEvent {
     pub state: KeyState,
     pub kind: CommandKind,
     pub elapsed: Option<Duration>,
+    // Whether it steps a cycle backwards
+    pub backward: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -180,6 +684,12 @@ impl Activation {
             kind: Some(ActivationKind::Trigger),
         }
     }
+    pub fn cycle(valid: bool, position: usize) -> Self {
+        Activation {
+            valid,
+            kind: Some(ActivationKind::Cycle { position }),
+        }
+    }
     pub fn failed() -> Self {
         Activation {
             valid: false,
@@ -191,11 +701,12 @@ impl Activation {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ActivationKind {
     Encoder,
     Switch { on: bool },
     Trigger,
+    Cycle { position: usize },
 }
 
 impl ActivationKind {
@@ -204,6 +715,7 @@ impl ActivationKind {
             Self::Encoder => CommandKind::Encoder,
             Self::Switch { on: _ } => CommandKind::Switch,
             Self::Trigger => CommandKind::Trigger,
+            Self::Cycle { position: _ } => CommandKind::Cycle,
         }
     }
 
@@ -218,6 +730,7 @@ impl Command {
             Self::Encoder(_) => CommandKind::Encoder,
             Self::Switch(_) => CommandKind::Switch,
             Self::Trigger(_) => CommandKind::Trigger,
+            Self::Cycle(_) => CommandKind::Cycle,
         }
     }
 }
@@ -232,14 +745,98 @@ pub struct KeyState {
     // value recorded at each detection
     pub detections: Vec<u8>,
     pub start: Instant,
-    pub initial_state: Option<InitialSwitchState>,
+    // Where a switch or cycle is, starting from its initial state. None for other kinds of control.
+    pub switch: Option<SwitchState>,
+}
+
+// Position of a switch among its states. A plain switch has two, OFF at 0 and ON at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwitchState {
+    pub position: usize,
+    pub count: usize,
+}
+
+impl SwitchState {
+    pub fn new(position: usize, count: usize) -> Self {
+        let count = count.max(1);
+        SwitchState {
+            position: position % count,
+            count,
+        }
+    }
+
+    // The state a press moves to, going back to the first one after the last
+    pub fn next(self) -> Self {
+        SwitchState::new(self.position + 1, self.count)
+    }
+
+    pub fn previous(self) -> Self {
+        SwitchState::new(self.position + self.count - 1, self.count)
+    }
+
+    pub fn is_on(self) -> bool {
+        self.position != 0
+    }
+}
+
+impl From<InitialSwitchState> for SwitchState {
+    // A switch with nothing to restore starts OFF
+    fn from(state: InitialSwitchState) -> Self {
+        match state {
+            InitialSwitchState::ON => SwitchState::new(1, 2),
+            InitialSwitchState::OFF | InitialSwitchState::Restore => SwitchState::new(0, 2),
+        }
+    }
+}
+
+impl From<SwitchState> for InitialSwitchState {
+    fn from(state: SwitchState) -> Self {
+        match state.is_on() {
+            true => InitialSwitchState::ON,
+            false => InitialSwitchState::OFF,
+        }
+    }
 }
 
 pub type ControlListByKey = HashMap<u8, String>; // HashMap<key code, control name>
 
 pub type ControlList = HashMap<String, InputOption>;
 
+pub type BankList = BTreeMap<String, ControlList>;
+
+// Names of controls, like `is_valid_control_name` checks them
+pub const CONTROL_NAME_PATTERN: &str = "^[a-z][a-z0-9_]+$";
+
+fn control_list_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = gen.subschema_for::<ControlList>().into_object();
+    let name = SchemaObject {
+        string: Some(Box::new(StringValidation {
+            pattern: Some(CONTROL_NAME_PATTERN.to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    schema.object().property_names = Some(Box::new(name.into()));
+    schema.into()
+}
+
+fn bank_list_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = gen.subschema_for::<BankList>().into_object();
+    schema.object().additional_properties = Some(Box::new(control_list_schema(gen)));
+    schema.into()
+}
+
 impl Config {
+    // The config as it's listened to while `bank` is active, with the controls of the bank
+    // added to the rest. An unknown bank adds none.
+    pub fn with_bank(&self, bank: Option<&str>) -> Config {
+        let mut config = self.clone();
+        if let Some(controls) = bank.and_then(|bank| self.banks.get(bank)) {
+            config.controls.extend(controls.clone());
+        }
+        config
+    }
+
     pub fn get_control(self: &Self, control: &String) -> Result<&InputOption, Error> {
         self.controls.get(control).ok_or(Error::msg(format!(
             "Control {} not found in the loaded config",
@@ -250,12 +847,44 @@ impl Config {
     pub fn get_controls_by_key(self: &Self) -> ControlListByKey {
         let mut list = HashMap::new();
 
+        // Pressure controls don't take their key, the hits of the same pad can be another control
         for control in self.controls.clone() {
-            list.insert(control.1.key(), control.0);
+            if control.1.pressure().is_none() {
+                list.insert(control.1.key(), control.0);
+            }
         }
         list
     }
 
+    // Controls reading the pressure a message carries
+    pub fn get_pressure_controls(&self, message: &MidiMessage) -> Vec<String> {
+        self.controls
+            .iter()
+            .filter(|(_, control)| match (control.pressure(), message.kind) {
+                (Some(PressureSource::PolyAftertouch), MessageType::PolyAftertouch) => {
+                    message.number == Some(control.key())
+                }
+                (Some(PressureSource::ChannelPressure), MessageType::ChannelPressure) => true,
+                _ => false,
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Keys that step a cycle backwards, with the key of the cycle they belong to
+    pub fn get_back_keys(&self) -> HashMap<u8, u8> {
+        self.controls
+            .values()
+            .filter_map(|control| match control.command() {
+                Command::Cycle(Cycle {
+                    back_key: Some(back_key),
+                    ..
+                }) => Some((back_key, control.key())),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn get_threshold(self: &Self, key: u8) -> Result<(CommandKind, Threshold), Error> {
         let by_key = self.get_controls_by_key();
         let control = by_key.get(&key).ok_or(Error::msg(format!(
@@ -270,8 +899,9 @@ impl Config {
                     Threshold::Full(self.thresholds.encoder),
                 ));
             }
-            CommandKind::Switch => {
-                return Ok((CommandKind::Switch, Threshold::Base(self.thresholds.switch)));
+            // Cycles are stepped like switches are toggled
+            kind @ (CommandKind::Switch | CommandKind::Cycle) => {
+                return Ok((kind, Threshold::Base(self.thresholds.switch)));
             }
             CommandKind::Trigger => {
                 return Ok((
@@ -282,3 +912,120 @@ impl Config {
         };
     }
 }
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum PortDirection {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    // Full port name as reported by ALSA, including the client and port ids
+    pub name: String,
+    pub client: String,
+    pub port: String,
+    pub client_id: Option<i32>,
+    pub port_id: Option<i32>,
+    pub direction: PortDirection,
+}
+
+impl PortInfo {
+    // ALSA names ports as `client name:port name client_id:port_id`
+    pub fn parse(name: &str, direction: PortDirection) -> Self {
+        let (names, ids) = match name.rsplit_once(' ') {
+            Some((names, ids)) if ids.contains(':') => (names, Some(ids)),
+            _ => (name, None),
+        };
+        let (client, port) = names.split_once(':').unwrap_or((names, names));
+        let (client_id, port_id) = match ids.and_then(|ids| ids.split_once(':')) {
+            Some((client_id, port_id)) => (client_id.parse().ok(), port_id.parse().ok()),
+            None => (None, None),
+        };
+
+        PortInfo {
+            name: name.to_string(),
+            client: client.to_string(),
+            port: port.to_string(),
+            client_id,
+            port_id,
+            direction,
+        }
+    }
+}
+
+// What the state file keeps of a device between runs, by control name
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SavedDevice {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub switches: BTreeMap<String, InitialSwitchState>,
+    // Last value each encoder was moved to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encoders: BTreeMap<String, u8>,
+}
+
+// Contents of the state file, by device name
+pub type SavedStates = BTreeMap<String, SavedDevice>;
+
+// A request sent to the running service through the control socket, one JSON object per line
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    // Configured devices and whether they are connected
+    Devices,
+    // Current state of the switches, of every device or just one
+    States {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    // Sets a switch without running its commands
+    Set {
+        device: String,
+        control: String,
+        state: InitialSwitchState,
+    },
+    // Runs a control as if it was pressed, with the given value for encoders
+    Fire {
+        device: String,
+        control: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<u8>,
+    },
+    // Makes another bank of controls of a device the active one
+    Bank {
+        device: String,
+        bank: String,
+    },
+    Reload,
+    LogLevel {
+        level: LogLevel,
+    },
+}
+
+// The answer to a `ControlRequest`, also one JSON object per line
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+impl ControlResponse {
+    pub fn success(result: Option<Value>) -> Self {
+        ControlResponse {
+            ok: true,
+            error: None,
+            result,
+        }
+    }
+
+    pub fn failure(error: &Error) -> Self {
+        ControlResponse {
+            ok: false,
+            error: Some(error.to_string()),
+            result: None,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use super::preprocess;
 use super::types::{self, LogLevel};
 use anyhow::Error;
 use chrono::{self, Duration};
@@ -5,35 +6,199 @@ use colored::*;
 use config::{Config, ConfigError};
 use core::fmt::Debug;
 use home::home_dir;
-use midir::{Ignore, MidiInput, MidiInputPort};
-use std::path::PathBuf;
+use midir::{Ignore, MidiIO, MidiInput, MidiInputPort, MidiOutput, MidiOutputPort};
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::ffi::OsString;
+use std::io::{stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Directory searched last, for configs shared by every user of the machine
+const SYSTEM_CONFIG_DIR: &str = "/etc/midiboard";
+
+// Level of the loggers made with `Logger::runtime`, shared so it can be changed while running
+static RUNTIME_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+// Looks for the config file. The first of these wins:
+// 1. The `--path` argument.
+// 2. The `MIDIBOARD_CONFIG` environment variable.
+// 3. The first existing file among `$XDG_CONFIG_HOME/midiboard/midiboard.*` (`~/.config` if unset),
+//    `$HOME/midiboard.*` and `/etc/midiboard/midiboard.*`, trying each format's extension in turn.
+// 4. `$HOME/midiboard.json` (or the extension of `--format`), where new files are created.
+pub fn find_config(
+    path: Option<&String>,
+    format: Option<&types::ConfigFormat>,
+) -> Result<types::ConfigLocation, ConfigError> {
+    find_config_with(path, format, |name| env::var_os(name))
+}
 
-pub fn read_user_config(path: Option<&String>) -> Result<types::ConfigFile, ConfigError> {
-    let mut fullpath = PathBuf::new();
+// Same as `find_config`, reading environment variables through `var`
+pub fn find_config_with<F: Fn(&str) -> Option<OsString>>(
+    path: Option<&String>,
+    format: Option<&types::ConfigFormat>,
+    var: F,
+) -> Result<types::ConfigLocation, ConfigError> {
+    let location = |path: PathBuf, source, searched| {
+        let format = config_format(&path, format);
+        Ok(types::ConfigLocation {
+            path,
+            format,
+            source,
+            searched,
+        })
+    };
+
+    if let Some(path) = path {
+        return location(
+            PathBuf::from(path),
+            types::ConfigSource::Argument,
+            Vec::new(),
+        );
+    }
+    if let Some(path) = var("MIDIBOARD_CONFIG").filter(|path| !path.is_empty()) {
+        return location(
+            PathBuf::from(path),
+            types::ConfigSource::Environment,
+            Vec::new(),
+        );
+    }
 
-    match path {
-        None => {
-            fullpath.push(
-                home_dir().ok_or(ConfigError::Message(String::from("Could not parse path")))?,
-            );
-            fullpath.push("midiboard");
-            fullpath.set_extension("json");
+    let home = var("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .or_else(home_dir)
+        .ok_or(ConfigError::Message(String::from("Could not parse path")))?;
+    let xdg = var("XDG_CONFIG_HOME")
+        .filter(|xdg| !xdg.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"));
+
+    let extensions: Vec<&str> = match format {
+        Some(format) => vec![format.extension()],
+        None => vec!["json", "yaml", "yml", "toml", "ron"],
+    };
+    let mut searched = Vec::new();
+    for dir in [
+        xdg.join("midiboard"),
+        home.clone(),
+        PathBuf::from(SYSTEM_CONFIG_DIR),
+    ] {
+        for extension in &extensions {
+            searched.push(dir.join("midiboard").with_extension(extension));
         }
-        Some(path) => fullpath.push(path),
     }
 
-    // load and return the config
-    let config = Config::builder()
-        .add_source(config::File::new(
-            fullpath
-                .as_os_str()
-                .to_str()
-                .ok_or(ConfigError::Message(String::from("Could not parse path")))?,
-            config::FileFormat::Json,
-        ))
-        .build();
-    let parsed_config = config?.try_deserialize::<types::ConfigFile>();
-    return parsed_config;
+    match searched.iter().find(|candidate| candidate.is_file()) {
+        Some(found) => location(found.clone(), types::ConfigSource::Search, searched.clone()),
+        None => location(
+            home.join("midiboard").with_extension(extensions[0]),
+            types::ConfigSource::Default,
+            searched,
+        ),
+    }
+}
+
+pub fn read_user_config(
+    path: Option<&String>,
+    format: Option<&types::ConfigFormat>,
+) -> Result<types::ConfigFile, ConfigError> {
+    let (fullpath, format) = locate_config(path, format)?;
+
+    // load and return the config, with everything it includes
+    let merged = preprocess::load(&fullpath, format)
+        .map_err(|error| ConfigError::Message(error.to_string()))?;
+    parse_config(&merged.to_string(), types::ConfigFormat::Json)
+}
+
+pub fn parse_config(
+    contents: &str,
+    format: types::ConfigFormat,
+) -> Result<types::ConfigFile, ConfigError> {
+    Config::builder()
+        .add_source(config::File::from_str(contents, format.file_format()))
+        .build()?
+        .try_deserialize::<types::ConfigFile>()
+}
+
+// The format given explicitly wins, then the one of the file extension. JSON is the default.
+pub fn config_format(path: &Path, format: Option<&types::ConfigFormat>) -> types::ConfigFormat {
+    format
+        .copied()
+        .or_else(|| types::ConfigFormat::from_path(path))
+        .unwrap_or(types::ConfigFormat::Json)
+}
+
+// Path and format of the config file, from the `--path` and `--format` arguments
+pub fn config_location(cli: &clap::ArgMatches) -> Result<(PathBuf, types::ConfigFormat), Error> {
+    Ok(locate_config(
+        cli.get_one::<String>("path"),
+        cli.get_one::<types::ConfigFormat>("format"),
+    )?)
+}
+
+fn locate_config(
+    path: Option<&String>,
+    format: Option<&types::ConfigFormat>,
+) -> Result<(PathBuf, types::ConfigFormat), ConfigError> {
+    let location = find_config(path, format)?;
+    Ok((location.path, location.format))
+}
+
+pub fn parse_value(contents: &str, format: types::ConfigFormat) -> Result<Value, Error> {
+    Ok(match format {
+        types::ConfigFormat::Json => serde_json::from_str(contents)?,
+        types::ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+        types::ConfigFormat::Toml => toml::from_str(contents)?,
+        types::ConfigFormat::Ron => ron::from_str(contents)?,
+    })
+}
+
+pub fn render_value(value: &Value, format: types::ConfigFormat) -> Result<String, Error> {
+    let mut rendered = match format {
+        types::ConfigFormat::Json => {
+            let mut buffer = Vec::new();
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+            let mut serializer = serde_json::Serializer::with_formatter(&mut buffer, formatter);
+            value.serialize(&mut serializer)?;
+            String::from_utf8(buffer)?
+        }
+        types::ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+        // TOML needs plain values before tables, which its own value type takes care of
+        types::ConfigFormat::Toml => toml::to_string_pretty(&toml::Value::try_from(value)?)?,
+        types::ConfigFormat::Ron => {
+            ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())?
+        }
+    };
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    Ok(rendered)
+}
+
+// Asks for a line on stdin. An empty answer picks the default, if there is one.
+pub fn prompt(question: &str, default: Option<&str>) -> Result<String, Error> {
+    match default {
+        Some(default) if !default.is_empty() => print!(
+            "{} {} ",
+            question.bright_cyan(),
+            format!("[{}]", default).as_str().dimmed()
+        ),
+        _ => print!("{} ", question.bright_cyan()),
+    }
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer)? == 0 {
+        return Err(Error::msg("Input closed before an answer was given."));
+    }
+
+    let answer = answer.trim();
+    match default {
+        Some(default) if answer.is_empty() => Ok(default.to_string()),
+        _ => Ok(answer.to_string()),
+    }
 }
 
 // From https://stackoverflow.com/a/52367953/16134348
@@ -44,26 +209,32 @@ pub fn string_to_sstr(s: String) -> &'static str {
 pub fn get_input_port(device: &str, log: Logger) -> Result<MidiInputPort, Error> {
     let mut input = MidiInput::new("Midiboard: Port Reader")?;
     input.ignore(Ignore::None);
-    match input.ports().len() {
+    find_port(&input, device, log)
+}
+
+pub fn get_output_port(device: &str, log: Logger) -> Result<MidiOutputPort, Error> {
+    let output = MidiOutput::new("Midiboard: Port Reader")?;
+    find_port(&output, device, log)
+}
+
+fn find_port<T: MidiIO>(io: &T, device: &str, log: Logger) -> Result<T::Port, Error> {
+    match io.ports().len() {
         0 => return Err(Error::msg("No ports detected. Is ALSA Seq running?")),
         _ => {
             log.trace("Ports detected", "");
             let mut selected_port: Option<usize> = None;
             let mut port_name_list = Vec::new();
 
-            for (index, port) in input.ports().iter().enumerate() {
+            for (index, port) in io.ports().iter().enumerate() {
                 log.trace(format!("Testing port {}", &index).as_str(), "");
-                let raw_name = input.port_name(port)?;
+                let raw_name = io.port_name(port)?;
                 port_name_list.push(raw_name);
             }
 
-            for (index, _) in input.ports().iter().enumerate() {
+            for (index, _) in io.ports().iter().enumerate() {
                 let port_name: &str = port_name_list[index].split(':').collect::<Vec<&str>>()[0];
 
-                let cleaned_name = port_name.to_lowercase().replace(" ", "");
-                let cleaned_device_name = &device.to_lowercase().replace(" ", "");
-
-                if cleaned_name.eq(cleaned_device_name) {
+                if matches_device(port_name, device) {
                     log.trace(
                         format!("Port {} matches device {}", &index, &device).as_str(),
                         "",
@@ -72,7 +243,7 @@ pub fn get_input_port(device: &str, log: Logger) -> Result<MidiInputPort, Error>
                 }
             }
             match selected_port {
-                Some(correct_port) => match input.ports().clone().get(correct_port) {
+                Some(correct_port) => match io.ports().get(correct_port) {
                     Some(port_connector) => Ok(port_connector.clone()),
                     None => Err(Error::msg("No valid port found. Probably the device was disconnected or the ports changed mid-connection.")),
                 },
@@ -90,6 +261,40 @@ pub fn get_input_port(device: &str, log: Logger) -> Result<MidiInputPort, Error>
     }
 }
 
+// Devices are matched against the ALSA client name, ignoring case and spaces
+pub fn matches_device(client_name: &str, device: &str) -> bool {
+    clean_name(client_name) == clean_name(device)
+}
+
+fn clean_name(name: &str) -> String {
+    name.to_lowercase().replace(' ', "")
+}
+
+// Closest candidate to `name`, if any is close enough to be a likely typo
+pub fn closest_match<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let cleaned = clean_name(name);
+    candidates
+        .iter()
+        .map(|candidate| (levenshtein(&cleaned, &clean_name(candidate)), *candidate))
+        .filter(|(distance, _)| *distance <= (cleaned.chars().count() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 pub fn ease_input(threshold: &Duration, elapsed: &Duration, value: u8) -> u8 {
     // Based off https://easings.net/#easeInOutQuad
     let threshold_milis = Duration::num_milliseconds(threshold);
@@ -124,17 +329,43 @@ pub fn interpolate(map_min: i32, map_max: i32, value: u8) -> Result<f64, Error>
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub struct Logger {
     current_level: LogLevel,
+    // Follows the shared runtime level instead of its own
+    runtime: bool,
 }
 
 impl Logger {
     pub fn new(level: LogLevel) -> Self {
         Logger {
             current_level: level,
+            runtime: false,
         }
     }
 
-    pub fn change_level(mut self: Self, level: LogLevel) {
-        self.current_level = level
+    // Logger for the running service, every copy of it follows `set_runtime_level`
+    pub fn runtime(level: LogLevel) -> Self {
+        RUNTIME_LEVEL.store(level as u8, Ordering::Relaxed);
+        Logger {
+            current_level: level,
+            runtime: true,
+        }
+    }
+
+    // Changes the level of every runtime logger
+    pub fn set_runtime_level(level: LogLevel) {
+        RUNTIME_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub fn level(self: Self) -> LogLevel {
+        if !self.runtime {
+            return self.current_level;
+        }
+        match RUNTIME_LEVEL.load(Ordering::Relaxed) {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            3 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
     }
 
     fn get_time(self: Self) -> String {
@@ -156,7 +387,7 @@ impl Logger {
     }
 
     pub fn info(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Info {
+        if self.level() >= LogLevel::Info {
             println!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -167,7 +398,7 @@ impl Logger {
     }
 
     pub fn debug(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Debug {
+        if self.level() >= LogLevel::Debug {
             println!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -178,7 +409,7 @@ impl Logger {
     }
 
     pub fn trace<T: Debug>(self: Self, message: &str, dump: T) {
-        if self.current_level >= LogLevel::Trace {
+        if self.level() >= LogLevel::Trace {
             let dump_formatted = format!("{:#?}", dump);
 
             println!(
@@ -197,7 +428,7 @@ impl Logger {
     }
 
     pub fn message(self: Self, message: &str, child: &str) {
-        if self.current_level >= LogLevel::Error {
+        if self.level() >= LogLevel::Error {
             let child_name = child.to_uppercase().magenta();
             println!(
                 "{} {} {} {}",
@@ -210,7 +441,7 @@ impl Logger {
     }
 
     pub fn fatal(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Error {
+        if self.level() >= LogLevel::Error {
             eprintln!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -223,7 +454,7 @@ impl Logger {
     }
 
     pub fn error(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Error {
+        if self.level() >= LogLevel::Error {
             eprintln!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -234,7 +465,7 @@ impl Logger {
     }
 
     pub fn warn(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Warn {
+        if self.level() >= LogLevel::Warn {
             eprintln!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -245,7 +476,7 @@ impl Logger {
     }
 
     pub fn success(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Error {
+        if self.level() >= LogLevel::Error {
             println!(
                 "{} {} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -256,7 +487,7 @@ impl Logger {
     }
 
     pub fn default(self: Self, message: &str) {
-        if self.current_level >= LogLevel::Error {
+        if self.level() >= LogLevel::Error {
             println!(
                 "{} {}",
                 format!("[{}]", self.get_time()).as_str().magenta(),
@@ -270,6 +501,7 @@ impl Default for Logger {
     fn default() -> Self {
         Logger {
             current_level: LogLevel::Debug,
+            runtime: false,
         }
     }
 }
@@ -0,0 +1,1334 @@
+use super::config;
+use super::document::{format_path, is_valid_control_name, ConfigDocument, Segment};
+use super::preprocess;
+use super::types::{self, ConfigFormat, PortDirection, PortInfo};
+use super::util;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Checks the config file field by field instead of relying on serde alone.
+// The untagged enums in `types` only report that nothing matched, so the structure is
+// walked here to say what is wrong and where, and the semantic rules serde can't express
+// are checked on top of it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // File the problem is in, the main config or one of its includes
+    pub file: PathBuf,
+    pub path: Vec<Segment>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Files that aren't JSON are checked after translating them, so there's no position to show
+        match self.line {
+            0 => write!(f, " {}", self.message)?,
+            _ => write!(f, "{}:{}: {}", self.line, self.column, self.message)?,
+        }
+        if !self.path.is_empty() {
+            write!(f, " (at {})", format_path(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+// Schema of the config types. The fields every object can have are read from it, so the checks
+// follow the types as they change.
+fn schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(config::schema)
+}
+
+fn definition(name: &str) -> &'static Value {
+    &schema()["definitions"][name]
+}
+
+// Each command kind with its schema, in the order of the `Command` variants
+fn command_kinds() -> Vec<(&'static str, &'static Value)> {
+    definition("Command")["oneOf"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|shape| Some((shape["properties"]["kind"]["enum"][0].as_str()?, shape)))
+        .collect()
+}
+
+// Fields of an object that are required in `required`, and the rest of the ones `known` has.
+// They are the same schema but for objects that can take either of two shapes.
+fn fields(
+    required: &'static Value,
+    known: &'static Value,
+) -> (Vec<&'static str>, Vec<&'static str>) {
+    let is_required = |name: &str| {
+        required["required"]
+            .as_array()
+            .is_some_and(|fields| fields.iter().any(|field| field == name))
+    };
+    known["properties"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, _)| name.as_str())
+        .partition(|name| is_required(name))
+}
+
+const LOG_LEVELS: [&str; 5] = ["Error", "Warn", "Info", "Debug", "Trace"];
+const MESSAGE_TYPES: [&str; 8] = [
+    "NoteOff",
+    "NoteOn",
+    "PolyAftertouch",
+    "ControlChange",
+    "ProgramChange",
+    "ChannelPressure",
+    "PitchBend",
+    "System",
+];
+
+// Checks the config file and every file it includes
+pub fn check_file(path: &Path, format: ConfigFormat) -> Result<Vec<Diagnostic>, anyhow::Error> {
+    let sources = match preprocess::collect(path, format) {
+        Ok(sources) => sources,
+        Err(error) => {
+            // Problems in the main file itself explain the failure better
+            let mut diagnostics = check_source(path, format, false, None)?;
+            if !diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error)
+            {
+                let (line, column) = locate(path, format, &key("include"));
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    file: path.to_path_buf(),
+                    path: key("include"),
+                    line,
+                    column,
+                    message: error.to_string(),
+                });
+            }
+            return Ok(diagnostics);
+        }
+    };
+
+    // Named commands can be used from any file, the first definition wins
+    let mut commands = Map::new();
+    for source in sources.iter().rev() {
+        if let Some(named) = source.value["commands"].as_object() {
+            commands.extend(named.clone());
+        }
+    }
+    let mut diagnostics = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        diagnostics.extend(check_source(
+            &source.path,
+            source.format,
+            index > 0,
+            Some(&commands),
+        )?);
+    }
+
+    for duplicate in preprocess::duplicates(&sources) {
+        let source = &sources[duplicate.source];
+        let (line, column) = locate(&source.path, source.format, &duplicate.path);
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file: source.path.clone(),
+            path: duplicate.path,
+            line,
+            column,
+            message: format!(
+                "{} `{}` is already defined in {}",
+                duplicate.kind,
+                duplicate.name,
+                duplicate.first.display()
+            ),
+        });
+    }
+    Ok(diagnostics)
+}
+
+fn check_source(
+    path: &Path,
+    format: ConfigFormat,
+    included: bool,
+    commands: Option<&Map<String, Value>>,
+) -> Result<Vec<Diagnostic>, anyhow::Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| anyhow::Error::msg(format!("Cannot read {:?}: {}", path, error)))?;
+    if format == ConfigFormat::Json {
+        return Ok(check_contents(path, &contents, included, commands));
+    }
+
+    let value = match util::parse_value(&contents, format) {
+        Ok(value) => value,
+        Err(error) => {
+            return Ok(vec![Diagnostic {
+                severity: Severity::Error,
+                file: path.to_path_buf(),
+                path: Vec::new(),
+                line: 0,
+                column: 0,
+                message: format!("Invalid {:?}: {}", format, error),
+            }])
+        }
+    };
+    let json = util::render_value(&value, ConfigFormat::Json)?;
+    let mut diagnostics = check_contents(path, &json, included, commands);
+    for diagnostic in diagnostics.iter_mut() {
+        diagnostic.line = 0;
+        diagnostic.column = 0;
+    }
+    Ok(diagnostics)
+}
+
+// Position of a field in a file, or nothing if the file isn't JSON
+fn locate(path: &Path, format: ConfigFormat, segments: &[Segment]) -> (usize, usize) {
+    if format != ConfigFormat::Json {
+        return (0, 0);
+    }
+    match ConfigDocument::load(path, format) {
+        Ok(document) => {
+            let offset = (0..=segments.len())
+                .rev()
+                .find_map(|length| document.position(&segments[..length]))
+                .unwrap_or_default();
+            document.line_col(offset)
+        }
+        Err(_) => (0, 0),
+    }
+}
+
+// Checks JSON text on its own, without following its includes
+#[cfg(test)]
+pub fn check_text(path: &Path, contents: &str) -> Vec<Diagnostic> {
+    check_contents(path, contents, false, None)
+}
+
+// `commands` holds the named commands of every file, without it only the ones of this file are known
+fn check_contents(
+    path: &Path,
+    contents: &str,
+    included: bool,
+    commands: Option<&Map<String, Value>>,
+) -> Vec<Diagnostic> {
+    // Syntax errors stop everything else, serde_json already knows where they are
+    if let Err(error) = serde_json::from_str::<Value>(contents) {
+        return vec![Diagnostic {
+            severity: Severity::Error,
+            file: path.to_path_buf(),
+            path: Vec::new(),
+            line: error.line(),
+            column: error.column(),
+            message: format!("Invalid JSON: {}", error),
+        }];
+    }
+    let document = match ConfigDocument::parse(path, contents) {
+        Ok(document) => document,
+        Err(error) => {
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                file: path.to_path_buf(),
+                path: Vec::new(),
+                line: 1,
+                column: 1,
+                message: error.to_string(),
+            }]
+        }
+    };
+
+    let own_commands = document.value()["commands"]
+        .as_object()
+        .cloned()
+        .unwrap_or_default();
+    let commands = commands.unwrap_or(&own_commands);
+    let mut checker = Checker {
+        document: &document,
+        commands: Some(commands),
+        diagnostics: Vec::new(),
+    };
+    match included {
+        true => checker.included(document.value()),
+        false => checker.root(document.value()),
+    }
+
+    // Anything the walk above missed is still caught by the real parser.
+    // Included files only hold devices, so only those are parsed.
+    let has_errors = checker
+        .diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error);
+    if !has_errors {
+        let mut value = document.value().clone();
+        value["commands"] = Value::Object(commands.clone());
+        // Included files may have no devices at all, only named commands
+        let parsed = match (preprocess::expand(&mut value), included) {
+            (Err(error), _) => Err(error.to_string()),
+            (Ok(()), true) => {
+                serde_json::from_value::<Option<Vec<types::Config>>>(value["config"].clone())
+                    .map(|_| ())
+                    .map_err(|error| error.to_string())
+            }
+            (Ok(()), false) => util::parse_config(&value.to_string(), ConfigFormat::Json)
+                .map(|_| ())
+                .map_err(|error| error.to_string()),
+        };
+        if let Err(error) = parsed {
+            checker.report(Severity::Error, &[], error);
+        }
+    }
+
+    checker.diagnostics
+}
+
+// Cross-checks the configured devices against the ports currently available
+pub fn probe(document: &ConfigDocument, ports: &[PortInfo]) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        document,
+        commands: None,
+        diagnostics: Vec::new(),
+    };
+    let devices = document.value()["config"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+
+    for (index, entry) in devices.iter().enumerate() {
+        let path = [key("config"), vec![Segment::Index(index)]].concat();
+        if let Some(name) = entry["device"].as_str() {
+            if entry["virtual_port"].as_bool() == Some(true) {
+                checker.report(
+                    Severity::Info,
+                    &child(&path, "device"),
+                    format!(
+                        "Device `{}` is a virtual port, it is created when running",
+                        name
+                    ),
+                );
+            } else {
+                checker.port(&child(&path, "device"), name, ports, PortDirection::Input);
+            }
+        }
+
+        let passthrough = &entry["passthrough"];
+        if let (Some(output), false) = (
+            passthrough["output"].as_str(),
+            passthrough["virtual_port"].as_bool() == Some(true),
+        ) {
+            let output_path = child(&child(&path, "passthrough"), "output");
+            checker.port(&output_path, output, ports, PortDirection::Output);
+        }
+    }
+    checker.diagnostics
+}
+
+// Devices of the file connected as inputs, by their index, with their names filled in like
+// `probe` does
+pub fn connected_devices(document: &ConfigDocument, ports: &[PortInfo]) -> Vec<(usize, String)> {
+    document.value()["config"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, entry)| entry["virtual_port"].as_bool() != Some(true))
+        .filter_map(|(index, entry)| Some((index, device_name(entry["device"].as_str()?))))
+        .filter(|(_, name)| {
+            ports.iter().any(|port| {
+                port.direction == PortDirection::Input && util::matches_device(&port.client, name)
+            })
+        })
+        .collect()
+}
+
+// A port or device name with its `${...}` references filled in, or as it is if they can't be
+fn device_name(name: &str) -> String {
+    preprocess::interpolate_text(name, &|name| env::var_os(name))
+        .unwrap_or_else(|_| name.to_string())
+}
+
+// Flags the keys of every listened device that were never received.
+// `heard` holds the keys received from each device, by its index in the config array.
+pub fn unheard_keys(
+    document: &ConfigDocument,
+    heard: &HashMap<usize, HashSet<u8>>,
+) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        document,
+        commands: None,
+        diagnostics: Vec::new(),
+    };
+    for (index, keys) in heard {
+        let controls = match document.value()["config"][*index]["controls"].as_object() {
+            Some(controls) => controls,
+            None => continue,
+        };
+        for (name, control) in controls {
+            let key = match control["key"].as_u64() {
+                Some(key) => key,
+                None => continue,
+            };
+            if !keys.iter().any(|heard| u64::from(*heard) == key) {
+                let path = [
+                    controls_path(*index),
+                    vec![
+                        Segment::Key(name.clone()),
+                        Segment::Key(String::from("key")),
+                    ],
+                ]
+                .concat();
+                checker.report(
+                    Severity::Warning,
+                    &path,
+                    format!(
+                        "Key {} of control `{}` was not received while listening",
+                        key, name
+                    ),
+                );
+            }
+        }
+    }
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    document: &'a ConfigDocument,
+    // Named commands that references are checked against
+    commands: Option<&'a Map<String, Value>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, severity: Severity, path: &[Segment], message: String) {
+        // Missing fields point at the object that should hold them
+        let offset = (0..=path.len())
+            .rev()
+            .find_map(|length| self.document.position(&path[..length]))
+            .unwrap_or_default();
+        let (line, column) = self.document.line_col(offset);
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file: self.document.path.clone(),
+            path: path.to_vec(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn port(&mut self, path: &[Segment], name: &str, ports: &[PortInfo], direction: PortDirection) {
+        let name = &device_name(name);
+        let ports: Vec<&PortInfo> = ports
+            .iter()
+            .filter(|port| port.direction == direction)
+            .collect();
+        let what = match direction {
+            PortDirection::Input => "Device",
+            PortDirection::Output => "Output port",
+        };
+
+        if ports
+            .iter()
+            .any(|port| util::matches_device(&port.client, name))
+        {
+            self.report(
+                Severity::Info,
+                path,
+                format!("{} `{}` is connected", what, name),
+            );
+            return;
+        }
+
+        // Ports are matched by their client name, so suggest the client even when the port name was closer
+        let mut candidates: Vec<&str> = ports.iter().map(|port| port.client.as_str()).collect();
+        candidates.extend(ports.iter().map(|port| port.port.as_str()));
+        let suggestion = util::closest_match(name, &candidates).and_then(|closest| {
+            ports
+                .iter()
+                .find(|port| port.client == closest || port.port == closest)
+        });
+
+        let message = match suggestion {
+            Some(port) if port.port != port.client => format!(
+                "{} `{}` was not found, did you mean '{}' (port '{}')?",
+                what, name, port.client, port.port
+            ),
+            Some(port) => format!(
+                "{} `{}` was not found, did you mean '{}'?",
+                what, name, port.client
+            ),
+            None => format!("{} `{}` was not found", what, name),
+        };
+        self.report(Severity::Warning, path, message);
+    }
+
+    // Reports missing required fields and unknown ones, and hands back the object if it is one
+    fn object<'v>(
+        &mut self,
+        path: &[Segment],
+        value: &'v Value,
+        required: &[&str],
+        optional: &[&str],
+    ) -> Option<&'v Map<String, Value>> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => {
+                self.report(Severity::Error, path, String::from("Expected an object"));
+                return None;
+            }
+        };
+        for field in required {
+            if !object.contains_key(*field) {
+                self.report(
+                    Severity::Error,
+                    path,
+                    format!("Missing required field `{}`", field),
+                );
+            }
+        }
+        for key in object.keys() {
+            if !required.contains(&key.as_str()) && !optional.contains(&key.as_str()) {
+                let known = [required, optional].concat().join("`, `");
+                self.report(
+                    Severity::Warning,
+                    &child(path, key),
+                    format!(
+                        "Unknown field `{}` is ignored, expected one of `{}`",
+                        key, known
+                    ),
+                );
+            }
+        }
+        Some(object)
+    }
+
+    fn string(&mut self, path: &[Segment], value: &Value) -> Option<String> {
+        let text = value.as_str().map(String::from);
+        if text.is_none() {
+            self.report(Severity::Error, path, String::from("Expected a string"));
+        }
+        text
+    }
+
+    // Reports `${...}` references that can't be filled in, and hands back the filled in text
+    fn interpolated(&mut self, path: &[Segment], text: &str) -> String {
+        match preprocess::interpolate_text(text, &|name| env::var_os(name)) {
+            Ok(text) => text,
+            Err(error) => {
+                self.report(Severity::Error, path, error.to_string());
+                text.to_string()
+            }
+        }
+    }
+
+    fn boolean(&mut self, path: &[Segment], value: &Value) {
+        if !value.is_boolean() {
+            self.report(
+                Severity::Error,
+                path,
+                String::from("Expected true or false"),
+            );
+        }
+    }
+
+    fn integer(&mut self, path: &[Segment], value: &Value, min: i64, max: i64) -> Option<i64> {
+        match value.as_i64() {
+            Some(number) if (min..=max).contains(&number) => Some(number),
+            _ => {
+                let expected = if max == i64::MAX {
+                    format!("Expected a whole number, {} or more", min)
+                } else {
+                    format!("Expected a whole number from {} to {}", min, max)
+                };
+                self.report(Severity::Error, path, expected);
+                None
+            }
+        }
+    }
+
+    fn variant(&mut self, path: &[Segment], value: &Value, variants: &[&str]) -> Option<String> {
+        let text = self.string(path, value)?;
+        if !variants.contains(&text.as_str()) {
+            self.report(
+                Severity::Error,
+                path,
+                format!(
+                    "Unknown value `{}`, expected one of `{}`",
+                    text,
+                    variants.join("`, `")
+                ),
+            );
+            return None;
+        }
+        Some(text)
+    }
+
+    fn root(&mut self, value: &Value) {
+        let (required, optional) = fields(schema(), schema());
+        let root = match self.object(&[], value, &required, &optional) {
+            Some(root) => root,
+            None => return,
+        };
+        if let Some(level) = root.get("log_level") {
+            self.variant(&key("log_level"), level, &LOG_LEVELS);
+        }
+        self.includes(root);
+        self.named_commands(root);
+        self.devices(root);
+    }
+
+    // Files pulled in with `include` only contribute devices
+    fn included(&mut self, value: &Value) {
+        let (required, optional) = fields(schema(), schema());
+        let root = match self.object(&[], value, &[], &[required, optional].concat()) {
+            Some(root) => root,
+            None => return,
+        };
+        if root.contains_key("log_level") {
+            self.report(
+                Severity::Warning,
+                &key("log_level"),
+                String::from("Ignored in included files, only the main config sets the log level"),
+            );
+        }
+        self.includes(root);
+        self.named_commands(root);
+        self.devices(root);
+    }
+
+    fn includes(&mut self, root: &Map<String, Value>) {
+        match root.get("include") {
+            Some(Value::Array(patterns)) => {
+                for (index, pattern) in patterns.iter().enumerate() {
+                    self.string(
+                        &[key("include"), vec![Segment::Index(index)]].concat(),
+                        pattern,
+                    );
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &key("include"),
+                String::from("Expected an array of file paths or globs"),
+            ),
+            None => {}
+        }
+    }
+
+    fn named_commands(&mut self, root: &Map<String, Value>) {
+        match root.get("commands") {
+            Some(Value::Object(commands)) => {
+                for (name, command) in commands {
+                    let path = child(&key("commands"), name);
+                    if command.get("use").is_some() {
+                        self.report(
+                            Severity::Error,
+                            &child(&path, "use"),
+                            String::from("Named commands can't use other named commands"),
+                        );
+                        continue;
+                    }
+                    self.command_data(&path, command);
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &key("commands"),
+                String::from("Expected an object of named commands"),
+            ),
+            None => {}
+        }
+    }
+
+    fn devices(&mut self, root: &Map<String, Value>) {
+        match root.get("config") {
+            Some(Value::Array(devices)) => {
+                for (index, device) in devices.iter().enumerate() {
+                    self.device(
+                        &[key("config"), vec![Segment::Index(index)]].concat(),
+                        device,
+                    );
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &key("config"),
+                String::from("Expected an array of devices"),
+            ),
+            None => {}
+        }
+    }
+
+    fn device(&mut self, path: &[Segment], value: &Value) {
+        let (required, optional) = fields(definition("Config"), definition("Config"));
+        let device = match self.object(path, value, &required, &optional) {
+            Some(device) => device,
+            None => return,
+        };
+        if let Some(name) = device.get("device") {
+            if let Some(name) = self.string(&child(path, "device"), name) {
+                self.interpolated(&child(path, "device"), &name);
+            }
+        }
+        if let Some(virtual_port) = device.get("virtual_port") {
+            self.boolean(&child(path, "virtual_port"), virtual_port);
+        }
+        if let Some(output) = device.get("feedback_output") {
+            if let Some(output) = self.string(&child(path, "feedback_output"), output) {
+                self.interpolated(&child(path, "feedback_output"), &output);
+            }
+        }
+        if let Some(thresholds) = device.get("thresholds") {
+            self.thresholds(&child(path, "thresholds"), thresholds);
+        }
+        if let Some(passthrough) = device.get("passthrough") {
+            self.passthrough(&child(path, "passthrough"), passthrough);
+        }
+        if let Some(on_shutdown) = device.get("on_shutdown") {
+            self.command_data(&child(path, "on_shutdown"), on_shutdown);
+        }
+
+        // Keys already taken in this device, with the control using them. Pressure controls have
+        // keys of their own, a pad can have one for its hits and one for its pressure.
+        let mut keys: HashMap<(Option<&str>, i64), String> = HashMap::new();
+        // Groups with a switch starting ON, with that switch
+        let mut groups_on: HashMap<&str, &String> = HashMap::new();
+        let controls = match device.get("controls") {
+            Some(controls) => self.controls(
+                &child(path, "controls"),
+                controls,
+                &mut keys,
+                &mut groups_on,
+            ),
+            None => None,
+        };
+
+        let banks_path = child(path, "banks");
+        let banks = match device.get("banks") {
+            Some(Value::Object(banks)) => Some(banks),
+            Some(_) => {
+                self.report(
+                    Severity::Error,
+                    &banks_path,
+                    String::from("Expected an object of named banks of controls"),
+                );
+                None
+            }
+            None => None,
+        };
+        // Each bank takes the keys left by `controls`, and can use the same ones as other banks
+        for (bank, bank_controls) in banks.into_iter().flatten() {
+            let bank_path = child(&banks_path, bank);
+            let (mut keys, mut groups_on) = (keys.clone(), groups_on.clone());
+            let names = self.controls(&bank_path, bank_controls, &mut keys, &mut groups_on);
+            for name in names.into_iter().flat_map(|names| names.keys()) {
+                if controls.is_some_and(|controls| controls.contains_key(name)) {
+                    self.report(
+                        Severity::Error,
+                        &child(&bank_path, name),
+                        format!("There is already a control named `{}` in `controls`", name),
+                    );
+                }
+            }
+        }
+        if let Some(initial_bank) = device.get("initial_bank") {
+            let initial_path = child(path, "initial_bank");
+            if let Some(bank) = self.string(&initial_path, initial_bank) {
+                if !banks.is_some_and(|banks| banks.contains_key(&bank)) {
+                    self.report(
+                        Severity::Error,
+                        &initial_path,
+                        format!("There is no bank named `{}`", bank),
+                    );
+                }
+            }
+        }
+    }
+
+    // Checks a list of controls, taking their keys and the groups they start ON. Returns the
+    // controls if it's a list of them.
+    fn controls<'v>(
+        &mut self,
+        path: &[Segment],
+        value: &'v Value,
+        keys: &mut HashMap<(Option<&'v str>, i64), String>,
+        groups_on: &mut HashMap<&'v str, &'v String>,
+    ) -> Option<&'v Map<String, Value>> {
+        let controls = match value {
+            Value::Object(controls) => controls,
+            _ => {
+                self.report(
+                    Severity::Error,
+                    path,
+                    String::from("Expected an object of named controls"),
+                );
+                return None;
+            }
+        };
+
+        for (name, control) in controls {
+            let control_path = child(path, name);
+            let command = control.get("command");
+            if let (Some("Switch"), Some(group), Some("ON")) = (
+                command.and_then(|command| command.get("kind")?.as_str()),
+                command.and_then(|command| command.get("group")?.as_str()),
+                command.and_then(|command| command.get("initial_state")?.as_str()),
+            ) {
+                if let Some(other) = groups_on.insert(group, name) {
+                    self.report(
+                        Severity::Warning,
+                        &child(&child(&control_path, "command"), "initial_state"),
+                        format!(
+                            "Only one switch of group `{}` can be ON, `{}` starts ON too",
+                            group, other
+                        ),
+                    );
+                }
+            }
+            if !is_valid_control_name(name) {
+                self.report(
+                    Severity::Warning,
+                    &control_path,
+                    format!(
+                        "Control name `{}` should be lowercase letters, digits or low dashes, starting with a letter",
+                        name
+                    ),
+                );
+            }
+            let source = command
+                .and_then(|command| command.get("source"))
+                .and_then(Value::as_str);
+            for (key_path, key) in self.control(&control_path, control) {
+                // Channel Pressure has no note, its key is not used
+                if source == Some("ChannelPressure") {
+                    continue;
+                }
+                match keys.get(&(source, key)) {
+                    Some(other) => self.report(
+                        Severity::Error,
+                        &key_path,
+                        format!("Key {} is already used by control `{}`", key, other),
+                    ),
+                    None => {
+                        keys.insert((source, key), name.clone());
+                    }
+                }
+            }
+        }
+        Some(controls)
+    }
+
+    fn thresholds(&mut self, path: &[Segment], value: &Value) {
+        let (required, optional) = fields(definition("Thresholds"), definition("Thresholds"));
+        let thresholds = match self.object(path, value, &required, &optional) {
+            Some(thresholds) => thresholds,
+            None => return,
+        };
+        if let Some(encoder) = thresholds.get("encoder") {
+            self.threshold(&child(path, "encoder"), encoder, true);
+        }
+        for kind in ["switch", "trigger"] {
+            if let Some(threshold) = thresholds.get(kind) {
+                self.threshold(&child(path, kind), threshold, false);
+            }
+        }
+    }
+
+    fn threshold(&mut self, path: &[Segment], value: &Value, needs_detection: bool) {
+        // Thresholds that don't need a detection one can still have it
+        let required = match needs_detection {
+            true => definition("FullTimeThreshold"),
+            false => definition("TimeThreshold"),
+        };
+        let (required, optional) = fields(required, definition("FullTimeThreshold"));
+        let threshold = match self.object(path, value, &required, &optional) {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let activation = threshold
+            .get("activation")
+            .and_then(|value| self.integer(&child(path, "activation"), value, 0, i64::MAX));
+        let detection = threshold
+            .get("detection")
+            .and_then(|value| self.integer(&child(path, "detection"), value, 0, i64::MAX));
+
+        if let (Some(activation), Some(detection)) = (activation, detection) {
+            if detection >= activation {
+                self.report(
+                    Severity::Error,
+                    &child(path, "detection"),
+                    format!(
+                        "Detection threshold ({} ms) must be less than the activation threshold ({} ms)",
+                        detection, activation
+                    ),
+                );
+            }
+        }
+    }
+
+    // Returns the valid keys the control listens to, with where they are
+    fn control(&mut self, path: &[Segment], value: &Value) -> Vec<(Vec<Segment>, i64)> {
+        // Controls without a threshold of their own are an `Input`, the rest an `InputOverride`
+        let (required, optional) = fields(definition("Input"), definition("InputOverride"));
+        let control = match self.object(path, value, &required, &optional) {
+            Some(control) => control,
+            None => return Vec::new(),
+        };
+        if let Some(threshold) = control.get("threshold") {
+            self.threshold(&child(path, "threshold"), threshold, false);
+        }
+        let mut keys = Vec::new();
+        if let Some(key) = control
+            .get("key")
+            .and_then(|key| self.integer(&child(path, "key"), key, 0, 127))
+        {
+            keys.push((child(path, "key"), key));
+        }
+        if let Some(command) = control.get("command") {
+            let command_path = child(path, "command");
+            if let Some(back_key) = self.command(&command_path, command) {
+                keys.push((child(&command_path, "back_key"), back_key));
+            }
+        }
+        keys
+    }
+
+    // Returns the back key of a cycle, if it has a valid one
+    fn command(&mut self, path: &[Segment], value: &Value) -> Option<i64> {
+        let kind = match value.get("kind") {
+            Some(kind) => self.variant(
+                &child(path, "kind"),
+                kind,
+                &command_kinds()
+                    .iter()
+                    .map(|(kind, _)| *kind)
+                    .collect::<Vec<&str>>(),
+            ),
+            None => {
+                self.object(path, value, &["kind"], &[]);
+                return None;
+            }
+        };
+        let shape = command_kinds()
+            .into_iter()
+            .find(|(name, _)| Some(*name) == kind.as_deref())
+            .map(|(_, shape)| shape)?;
+        let (required, optional) = fields(shape, shape);
+        let command = self.object(path, value, &required, &optional)?;
+        // Fields holding a command, which can also be a named one
+        for (field, data) in command {
+            if shape["properties"][field]["$ref"] == "#/definitions/CommandDefinition" {
+                self.command_data(&child(path, field), data);
+            }
+        }
+        match kind.as_deref() {
+            Some("Cycle") => return self.cycle(path, command),
+            Some("Trigger") => self.zones(path, command),
+            _ => {}
+        }
+        if let Some(source) = command.get("source") {
+            self.variant(
+                &child(path, "source"),
+                source,
+                &["PolyAftertouch", "ChannelPressure"],
+            );
+        }
+        if let Some(state) = command.get("initial_state") {
+            self.variant(
+                &child(path, "initial_state"),
+                state,
+                &["ON", "OFF", "restore"],
+            );
+        }
+        if let Some(group) = command.get("group") {
+            self.string(&child(path, "group"), group);
+        }
+        if let Some(silent) = command.get("group_silent") {
+            self.boolean(&child(path, "group_silent"), silent);
+        }
+        if let Some(momentary) = command.get("momentary") {
+            let initial = command.get("initial_state").and_then(Value::as_str);
+            self.boolean(&child(path, "momentary"), momentary);
+            if momentary == &Value::Bool(true) && initial.is_some_and(|initial| initial != "OFF") {
+                self.report(
+                    Severity::Warning,
+                    &child(path, "initial_state"),
+                    String::from("A momentary switch is only ON while held, it should start OFF"),
+                );
+            }
+        }
+        None
+    }
+
+    // Returns the back key of the cycle, if it is valid
+    fn cycle(&mut self, path: &[Segment], command: &Map<String, Value>) -> Option<i64> {
+        let states_path = child(path, "states");
+        let mut names = Vec::new();
+        match command.get("states") {
+            Some(Value::Array(states)) if !states.is_empty() => {
+                for (index, state) in states.iter().enumerate() {
+                    let mut state_path = states_path.clone();
+                    state_path.push(Segment::Index(index));
+                    let (required, optional) =
+                        fields(definition("CycleState"), definition("CycleState"));
+                    let state = match self.object(&state_path, state, &required, &optional) {
+                        Some(state) => state,
+                        None => continue,
+                    };
+                    if let Some(execute) = state.get("execute") {
+                        self.command_data(&child(&state_path, "execute"), execute);
+                    }
+                    if let Some(feedback) = state.get("feedback") {
+                        self.integer(&child(&state_path, "feedback"), feedback, 0, 127);
+                    }
+                    let name = match state.get("name") {
+                        Some(name) => self.string(&child(&state_path, "name"), name),
+                        None => None,
+                    };
+                    if let Some(name) = name {
+                        if names.contains(&name) {
+                            self.report(
+                                Severity::Error,
+                                &child(&state_path, "name"),
+                                format!("There is already a state named `{}`", name),
+                            );
+                        }
+                        names.push(name);
+                    }
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &states_path,
+                String::from("Expected a non-empty array of states"),
+            ),
+            None => {}
+        }
+        if let Some(initial_state) = command.get("initial_state") {
+            let initial_path = child(path, "initial_state");
+            if let Some(name) = self.string(&initial_path, initial_state) {
+                if !names.is_empty() && !names.contains(&name) {
+                    self.report(
+                        Severity::Error,
+                        &initial_path,
+                        format!("There is no state named `{}`", name),
+                    );
+                }
+            }
+        }
+        if let Some(back_values) = command.get("back_values") {
+            self.range(&child(path, "back_values"), back_values, 0, 127);
+        }
+        command
+            .get("back_key")
+            .and_then(|key| self.integer(&child(path, "back_key"), key, 0, 127))
+    }
+
+    fn zones(&mut self, path: &[Segment], command: &Map<String, Value>) {
+        let zones_path = child(path, "zones");
+        let zones = match command.get("zones") {
+            Some(Value::Array(zones)) => zones,
+            Some(_) => {
+                return self.report(
+                    Severity::Error,
+                    &zones_path,
+                    String::from("Expected an array of zones"),
+                )
+            }
+            None if command.contains_key("execute") => return,
+            None => {
+                return self.report(
+                    Severity::Error,
+                    path,
+                    String::from("A trigger needs `execute`, `zones` or both"),
+                )
+            }
+        };
+        // Valid ranges of the zones before each one, to find overlaps
+        let mut ranges: Vec<(usize, i64, i64)> = Vec::new();
+        for (index, zone) in zones.iter().enumerate() {
+            let mut zone_path = zones_path.clone();
+            zone_path.push(Segment::Index(index));
+            let (required, optional) =
+                fields(definition("VelocityZone"), definition("VelocityZone"));
+            let zone = match self.object(&zone_path, zone, &required, &optional) {
+                Some(zone) => zone,
+                None => continue,
+            };
+            if let Some(execute) = zone.get("execute") {
+                self.command_data(&child(&zone_path, "execute"), execute);
+            }
+            let values = match zone.get("values") {
+                Some(values) => values,
+                None => continue,
+            };
+            self.range(&child(&zone_path, "values"), values, 0, 127);
+            let (min, max) = match (values["min"].as_i64(), values["max"].as_i64()) {
+                (Some(min), Some(max)) if min <= max => (min, max),
+                _ => continue,
+            };
+            if let Some((other, ..)) = ranges
+                .iter()
+                .find(|(_, other_min, other_max)| min <= *other_max && *other_min <= max)
+            {
+                self.report(
+                    Severity::Warning,
+                    &child(&zone_path, "values"),
+                    format!(
+                        "Overlaps zone {}, which is executed for the velocities in both",
+                        other
+                    ),
+                );
+            }
+            ranges.push((index, min, max));
+        }
+    }
+
+    fn command_data(&mut self, path: &[Segment], value: &Value) {
+        if value.get("use").is_some() {
+            self.command_use(path, value);
+            return;
+        }
+        let (required, optional) = fields(definition("CommandData"), definition("CommandData"));
+        let data = match self.object(path, value, &required, &optional) {
+            Some(data) => data,
+            None => return,
+        };
+        let replace = data
+            .get("replace")
+            .and_then(|replace| self.string(&child(path, "replace"), replace));
+        let velocity = data
+            .get("velocity")
+            .and_then(|velocity| self.string(&child(path, "velocity"), velocity));
+        if let Some(args) = data.get("args") {
+            match args.as_array() {
+                Some(args) => {
+                    for (index, arg) in args.iter().enumerate() {
+                        let mut arg_path = child(path, "args");
+                        arg_path.push(Segment::Index(index));
+                        if let Some(arg) = self.string(&arg_path, arg) {
+                            self.interpolated(&arg_path, &arg);
+                        }
+                    }
+                }
+                None => self.report(
+                    Severity::Error,
+                    &child(path, "args"),
+                    String::from("Expected an array of strings"),
+                ),
+            }
+        }
+        let (min, max) = (i32::MIN.into(), i32::MAX.into());
+        let map_min = data
+            .get("map_min")
+            .and_then(|value| self.integer(&child(path, "map_min"), value, min, max));
+        let map_max = data
+            .get("map_max")
+            .and_then(|value| self.integer(&child(path, "map_max"), value, min, max));
+
+        if replace.is_some() {
+            for field in ["map_min", "map_max"] {
+                if !data.contains_key(field) {
+                    self.report(
+                        Severity::Error,
+                        path,
+                        format!("`replace` is set but `{}` is missing", field),
+                    );
+                }
+            }
+        }
+        if let (Some(min), Some(max)) = (map_min, map_max) {
+            if min > max {
+                self.report(
+                    Severity::Error,
+                    &child(path, "map_min"),
+                    format!("`map_min` ({}) is more than `map_max` ({})", min, max),
+                );
+            }
+        }
+
+        if let Some(cmd) = data
+            .get("cmd")
+            .and_then(|cmd| self.string(&child(path, "cmd"), cmd))
+        {
+            let cmd = self.interpolated(&child(path, "cmd"), &cmd);
+            // Commands built from the value or parameters can't be looked up ahead of time
+            let is_templated = [replace, velocity]
+                .into_iter()
+                .flatten()
+                .any(|placeholder| cmd.contains(&placeholder))
+                || !preprocess::parameters(&Value::String(cmd.clone())).is_empty();
+            if !is_templated && find_executable(&cmd).is_none() {
+                self.report(
+                    Severity::Warning,
+                    &child(path, "cmd"),
+                    format!("Command `{}` was not found in $PATH", cmd),
+                );
+            }
+        }
+    }
+
+    fn command_use(&mut self, path: &[Segment], value: &Value) {
+        let (required, optional) = fields(definition("CommandUse"), definition("CommandUse"));
+        let reference = match self.object(path, value, &required, &optional) {
+            Some(reference) => reference,
+            None => return,
+        };
+        self.string(&child(path, "use"), &reference["use"]);
+        match reference.get("with") {
+            Some(Value::Object(with)) => {
+                for (parameter, value) in with {
+                    self.string(&child(&child(path, "with"), parameter), value);
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &child(path, "with"),
+                String::from("Expected an object of parameter values"),
+            ),
+            None => {}
+        }
+
+        if let Some(commands) = self.commands {
+            if let Err(error) = preprocess::expand_command(commands, value) {
+                self.report(Severity::Error, path, error.to_string());
+            }
+        }
+    }
+
+    fn passthrough(&mut self, path: &[Segment], value: &Value) {
+        let (required, optional) = fields(definition("Passthrough"), definition("Passthrough"));
+        let passthrough = match self.object(path, value, &required, &optional) {
+            Some(passthrough) => passthrough,
+            None => return,
+        };
+        if let Some(output) = passthrough.get("output") {
+            if let Some(output) = self.string(&child(path, "output"), output) {
+                self.interpolated(&child(path, "output"), &output);
+            }
+        }
+        if let Some(virtual_port) = passthrough.get("virtual_port") {
+            self.boolean(&child(path, "virtual_port"), virtual_port);
+        }
+        if let Some(forward) = passthrough.get("forward") {
+            self.variant(&child(path, "forward"), forward, &["Unconsumed", "All"]);
+        }
+        for field in ["include", "exclude"] {
+            let filters_path = child(path, field);
+            match passthrough.get(field) {
+                Some(Value::Array(filters)) => {
+                    for (index, filter) in filters.iter().enumerate() {
+                        let mut filter_path = filters_path.clone();
+                        filter_path.push(Segment::Index(index));
+                        self.filter(&filter_path, filter);
+                    }
+                }
+                Some(_) => self.report(
+                    Severity::Error,
+                    &filters_path,
+                    String::from("Expected an array of filters"),
+                ),
+                None => {}
+            }
+        }
+    }
+
+    fn filter(&mut self, path: &[Segment], value: &Value) {
+        let (required, optional) = fields(definition("MessageFilter"), definition("MessageFilter"));
+        let filter = match self.object(path, value, &required, &optional) {
+            Some(filter) => filter,
+            None => return,
+        };
+        match filter.get("types") {
+            Some(Value::Array(types)) => {
+                for (index, kind) in types.iter().enumerate() {
+                    let mut type_path = child(path, "types");
+                    type_path.push(Segment::Index(index));
+                    self.variant(&type_path, kind, &MESSAGE_TYPES);
+                }
+            }
+            Some(_) => self.report(
+                Severity::Error,
+                &child(path, "types"),
+                String::from("Expected an array of message types"),
+            ),
+            None => {}
+        }
+        if let Some(range) = filter.get("channels") {
+            self.range(&child(path, "channels"), range, 1, 16);
+        }
+        if let Some(range) = filter.get("keys") {
+            self.range(&child(path, "keys"), range, 0, 127);
+        }
+    }
+
+    fn range(&mut self, path: &[Segment], value: &Value, lowest: i64, highest: i64) {
+        let (required, optional) = fields(definition("ValueRange"), definition("ValueRange"));
+        let range = match self.object(path, value, &required, &optional) {
+            Some(range) => range,
+            None => return,
+        };
+        let min = range
+            .get("min")
+            .and_then(|min| self.integer(&child(path, "min"), min, lowest, highest));
+        let max = range
+            .get("max")
+            .and_then(|max| self.integer(&child(path, "max"), max, lowest, highest));
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                self.report(
+                    Severity::Error,
+                    path,
+                    format!("Range starts at {} but ends at {}", min, max),
+                );
+            }
+        }
+    }
+}
+
+fn controls_path(device: usize) -> Vec<Segment> {
+    vec![
+        Segment::Key(String::from("config")),
+        Segment::Index(device),
+        Segment::Key(String::from("controls")),
+    ]
+}
+
+fn key(name: &str) -> Vec<Segment> {
+    vec![Segment::Key(name.to_string())]
+}
+
+fn child(path: &[Segment], name: &str) -> Vec<Segment> {
+    let mut path = path.to_vec();
+    path.push(Segment::Key(name.to_string()));
+    path
+}
+
+// Resolves a command the way the shell would, looking through $PATH unless it has a slash
+pub fn find_executable(cmd: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+
+    if cmd.contains('/') {
+        let path = PathBuf::from(cmd);
+        return is_executable(&path).then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(cmd))
+        .find(|path| is_executable(path))
+}